@@ -0,0 +1,43 @@
+// Compares the conversions of typical commit strings.
+// Run with `cargo bench --bench encoding`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use xcb_imdkit::encoding::*;
+
+const ITERATIONS: u32 = 100_000;
+
+const COMMIT_STRINGS: [&str; 4] = ["a", "hello", "café", "日本語"];
+
+fn bench(name: &str, s: &str, mut f: impl FnMut()) {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed: Duration = start.elapsed();
+    println!(
+        "{:<28} {:<10} {:>8.1} ns/iter",
+        name,
+        format!("{:?}", s),
+        elapsed.as_nanos() as f64 / ITERATIONS as f64
+    );
+}
+
+fn main() {
+    for s in COMMIT_STRINGS.iter() {
+        bench("utf8_to_compound_text", s, || {
+            black_box(utf8_to_compound_text(black_box(s.as_bytes())).unwrap());
+        });
+
+        let mut buf = Vec::new();
+        bench("utf8_to_compound_text_into", s, || {
+            buf.clear();
+            utf8_to_compound_text_into(black_box(s.as_bytes()), &mut buf).unwrap();
+            black_box(&buf);
+        });
+
+        bench("utf8_to_compound_text_cow", s, || {
+            black_box(utf8_to_compound_text_cow(black_box(s.as_bytes())).unwrap());
+        });
+    }
+}
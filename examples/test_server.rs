@@ -62,7 +62,7 @@ fn main() {
         &keys,
         &keys,
         &encoding_array,
-        0,
+        EventMask::empty(),
         handler,
     );
 
@@ -100,15 +100,16 @@ impl<'a> ImMessageHandler for TestServerHandler<'a> {
         _client: &ImClient,
         ic: &InputContext,
         _frame: &ForwardEventMessage,
-        key_event: &xcb::KeyPressEvent,
+        event: &ForwardedEvent,
     ) {
-        let sym = self.key_symbols.press_lookup_keysym(key_event, 0);
-        if sym == 't' as xcb::Keysym {
-            let result = xcb_imdkit::encoding::utf8_to_compound_text(TEST_STRING.as_bytes())
-                .expect("failed to convert to CTEXT");
-            im.commit_string(ic, &CommittedString::Chars(&result));
-        } else {
-            im.forward_event(ic, key_event);
+        match event.key_press() {
+            Some(key_event)
+                if self.key_symbols.press_lookup_keysym(key_event, 0) == 't' as xcb::Keysym =>
+            {
+                im.commit_str(ic, TEST_STRING)
+                    .expect("failed to convert to CTEXT");
+            }
+            _ => im.forward_event(ic, event.as_generic_event()),
         }
     }
 }
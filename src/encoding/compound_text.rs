@@ -0,0 +1,1036 @@
+// Compound Text Encoding, Version 1.1 (X Consortium Standard)
+
+use super::ConvertError;
+use encoding_rs::{DecoderResult, EncoderResult};
+use std::borrow::Borrow;
+use std::fmt;
+use std::ops::Deref;
+
+const ESC: u8 = 0x1b;
+const CSI: u8 = 0x9b;
+const STX: u8 = 0x02;
+
+/// The maximum length of an extended segment, following `M` and `L`
+const MAX_EXTENDED_SEGMENT_LENGTH: usize = 128 * 128 - 1;
+
+const BIG5_SEGMENT_NAME: &[u8] = b"big5-0";
+
+/// A character set which can appear in compound text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Charset {
+    /// The left half of ISO 8859-1, which is the initial GL.
+    Ascii,
+    JisX0201Roman,
+    JisX0201Katakana,
+    /// The right half of ISO 8859-n. ISO 8859-1 is the initial GR.
+    Iso8859(u8),
+    JisX0208,
+    Gb2312,
+    KsC5601,
+    /// Big5 in an extended segment.
+    Big5,
+    /// UTF-8 in an extended segment or between `ESC % G` and `ESC % @`.
+    Utf8,
+}
+
+// The parts of ISO 8859 in the order to try when encoding, with their final bytes
+const ISO8859_PARTS: [(u8, u8); 14] = [
+    (1, b'A'),
+    (2, b'B'),
+    (3, b'C'),
+    (4, b'D'),
+    (5, b'L'),
+    (6, b'G'),
+    (7, b'F'),
+    (8, b'H'),
+    (9, b'M'),
+    (10, b'V'),
+    (13, b'Y'),
+    (14, b'_'),
+    (15, b'b'),
+    (16, b'f'),
+];
+
+impl Charset {
+    fn from_94_final(f: u8) -> Option<Charset> {
+        match f {
+            b'B' => Some(Charset::Ascii),
+            b'J' => Some(Charset::JisX0201Roman),
+            b'I' => Some(Charset::JisX0201Katakana),
+            _ => None,
+        }
+    }
+
+    fn from_96_final(f: u8) -> Option<Charset> {
+        ISO8859_PARTS
+            .iter()
+            .find(|(_, x)| *x == f)
+            .map(|(part, _)| Charset::Iso8859(*part))
+    }
+
+    fn from_94n_final(f: u8) -> Option<Charset> {
+        match f {
+            b'A' => Some(Charset::Gb2312),
+            b'B' => Some(Charset::JisX0208),
+            b'C' => Some(Charset::KsC5601),
+            _ => None,
+        }
+    }
+
+    fn from_extended_segment_name(name: &[u8]) -> Option<Charset> {
+        if name.eq_ignore_ascii_case(BIG5_SEGMENT_NAME) {
+            Some(Charset::Big5)
+        } else if name.eq_ignore_ascii_case(b"iso10646-1") || name.eq_ignore_ascii_case(b"utf-8") {
+            Some(Charset::Utf8)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the escape sequence designating the charset, or `None` for charsets in segments.
+    fn designation(self, gr: bool) -> Option<[u8; 4]> {
+        let i = if gr { b')' } else { b'(' };
+        match self {
+            Charset::Ascii => Some([ESC, i, b'B', 0]),
+            Charset::JisX0201Roman => Some([ESC, i, b'J', 0]),
+            Charset::JisX0201Katakana => Some([ESC, i, b'I', 0]),
+            Charset::Iso8859(part) => {
+                let f = ISO8859_PARTS.iter().find(|(x, _)| *x == part)?.1;
+                Some([ESC, b'-', f, 0])
+            }
+            Charset::Gb2312 => Some([ESC, b'$', i, b'A']),
+            Charset::JisX0208 => Some([ESC, b'$', i, b'B']),
+            Charset::KsC5601 => Some([ESC, b'$', i, b'C']),
+            Charset::Big5 | Charset::Utf8 => None,
+        }
+    }
+
+    fn bytes_per_char(self) -> usize {
+        match self {
+            Charset::JisX0208 | Charset::Gb2312 | Charset::KsC5601 => 2,
+            _ => 1,
+        }
+    }
+
+    fn is_96(self) -> bool {
+        matches!(self, Charset::Iso8859(_))
+    }
+
+    fn iso8859_encoding(part: u8) -> Option<&'static encoding_rs::Encoding> {
+        match part {
+            2 => Some(encoding_rs::ISO_8859_2),
+            3 => Some(encoding_rs::ISO_8859_3),
+            4 => Some(encoding_rs::ISO_8859_4),
+            5 => Some(encoding_rs::ISO_8859_5),
+            6 => Some(encoding_rs::ISO_8859_6),
+            7 => Some(encoding_rs::ISO_8859_7),
+            8 => Some(encoding_rs::ISO_8859_8),
+            // The right half of windows-1254 is the same as ISO 8859-9
+            9 => Some(encoding_rs::WINDOWS_1254),
+            10 => Some(encoding_rs::ISO_8859_10),
+            13 => Some(encoding_rs::ISO_8859_13),
+            14 => Some(encoding_rs::ISO_8859_14),
+            15 => Some(encoding_rs::ISO_8859_15),
+            16 => Some(encoding_rs::ISO_8859_16),
+            _ => None,
+        }
+    }
+
+    /// Decodes a character given in GR bytes.
+    fn decode_char(self, bytes: &[u8], out: &mut String) -> bool {
+        let b = bytes[0];
+        let c = match self {
+            Charset::Ascii => b & 0x7f,
+            Charset::JisX0201Roman => match b & 0x7f {
+                0x5c => {
+                    out.push('\u{a5}');
+                    return true;
+                }
+                0x7e => {
+                    out.push('\u{203e}');
+                    return true;
+                }
+                x => x,
+            },
+            Charset::JisX0201Katakana => {
+                return match b {
+                    0xa1..=0xdf => {
+                        out.extend(std::char::from_u32(0xff61 + (b - 0xa1) as u32));
+                        true
+                    }
+                    _ => false,
+                };
+            }
+            Charset::Iso8859(1) => {
+                out.push(b as char);
+                return true;
+            }
+            Charset::Iso8859(part) => match Self::iso8859_encoding(part) {
+                Some(encoding) => return decode_with(encoding, bytes, out, false).is_ok(),
+                None => return false,
+            },
+            Charset::JisX0208 => {
+                return decode_with(encoding_rs::EUC_JP, bytes, out, false).is_ok()
+            }
+            Charset::Gb2312 => return decode_with(encoding_rs::GBK, bytes, out, false).is_ok(),
+            Charset::KsC5601 => return decode_with(encoding_rs::EUC_KR, bytes, out, false).is_ok(),
+            Charset::Big5 | Charset::Utf8 => return false,
+        };
+        out.push(c as char);
+        true
+    }
+
+    /// Returns the charset to encode `c` and its bytes, with the high bits set.
+    fn encode_char(c: char) -> Option<(Charset, [u8; 4], usize)> {
+        let code = c as u32;
+        let single = |charset, b: u8| Some((charset, [b, 0, 0, 0], 1));
+
+        if (0x20..0x7f).contains(&code) {
+            return single(Charset::Ascii, code as u8);
+        }
+        if (0xa0..=0xff).contains(&code) {
+            return single(Charset::Iso8859(1), code as u8);
+        }
+        if code < 0xa0 {
+            // Compound text allows no controls other than HT and NL, even in UTF-8 segments
+            return None;
+        }
+        if (0xff61..=0xff9f).contains(&code) {
+            return single(Charset::JisX0201Katakana, (code - 0xff61) as u8 + 0xa1);
+        }
+
+        let mut buf = [0u8; 4];
+        // No part of ISO 8859 has characters after U+2116
+        let iso8859_parts = if code <= 0x2116 {
+            &ISO8859_PARTS[1..]
+        } else {
+            &[]
+        };
+        for &(part, _) in iso8859_parts {
+            let encoding = Self::iso8859_encoding(part).unwrap();
+            if encode_with(encoding, c, &mut buf) == Some(1) && buf[0] >= 0xa0 {
+                return Some((Charset::Iso8859(part), buf, 1));
+            }
+        }
+
+        // The cells of the standards, without vendor and user-defined ones such as NEC row 13
+        let double_byte = [
+            (
+                Charset::JisX0208,
+                encoding_rs::EUC_JP,
+                is_jis_x0208 as fn(u8, u8) -> bool,
+            ),
+            (Charset::Gb2312, encoding_rs::GBK, is_gb2312),
+            (Charset::KsC5601, encoding_rs::EUC_KR, is_ks_c_5601),
+        ];
+        for (charset, encoding, is_defined) in double_byte.iter() {
+            if encode_with(encoding, c, &mut buf) == Some(2)
+                && buf[1] >= 0xa1
+                && is_defined(buf[0], buf[1])
+            {
+                return Some((*charset, buf, 2));
+            }
+        }
+
+        if encode_with(encoding_rs::BIG5, c, &mut buf) == Some(2) && (0xa1..=0xf9).contains(&buf[0])
+        {
+            return Some((Charset::Big5, buf, 2));
+        }
+
+        utf8_char(c)
+    }
+}
+
+fn is_jis_x0208(lead: u8, _trail: u8) -> bool {
+    matches!(lead, 0xa1..=0xa8 | 0xb0..=0xf4)
+}
+
+// GBK also has characters in the rows of GB 2312, such as U+2170 at A2A1 and PUA in row 2
+fn is_gb2312(lead: u8, trail: u8) -> bool {
+    match lead {
+        // GBK has U+2014 at A1AA, where GB 2312 has U+2015
+        0xa1 => trail != 0xaa,
+        0xa3 | 0xb0..=0xd6 | 0xd8..=0xf7 => true,
+        0xa2 => matches!(trail, 0xb1..=0xe2 | 0xe5..=0xee | 0xf1..=0xfc),
+        0xa4 => trail <= 0xf3,
+        0xa5 => trail <= 0xf6,
+        0xa6 => matches!(trail, 0xa1..=0xb8 | 0xc1..=0xd8),
+        0xa7 => matches!(trail, 0xa1..=0xc1 | 0xd1..=0xf1),
+        0xa8 => matches!(trail, 0xa1..=0xba | 0xc5..=0xe9),
+        0xa9 => matches!(trail, 0xa4..=0xef),
+        0xd7 => trail <= 0xf9,
+        _ => false,
+    }
+}
+
+fn is_ks_c_5601(lead: u8, _trail: u8) -> bool {
+    matches!(lead, 0xa1..=0xac | 0xb0..=0xc8 | 0xca..=0xfd)
+}
+
+fn utf8_char(c: char) -> Option<(Charset, [u8; 4], usize)> {
+    let mut buf = [0u8; 4];
+    let len = c.encode_utf8(&mut buf).len();
+    Some((Charset::Utf8, buf, len))
+}
+
+// Returns the offset of the first malformed character unless `lossy`
+fn decode_with(
+    encoding: &'static encoding_rs::Encoding,
+    bytes: &[u8],
+    out: &mut String,
+    lossy: bool,
+) -> Result<(), usize> {
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    if lossy {
+        if let Some(len) = decoder.max_utf8_buffer_length(bytes.len()) {
+            out.reserve(len);
+        }
+        // The output never gets full with the reserved capacity
+        let _ = decoder.decode_to_string(bytes, out, true);
+        return Ok(());
+    }
+
+    if let Some(len) = decoder.max_utf8_buffer_length_without_replacement(bytes.len()) {
+        out.reserve(len);
+    }
+    match decoder.decode_to_string_without_replacement(bytes, out, true) {
+        (DecoderResult::InputEmpty, _) => Ok(()),
+        (DecoderResult::Malformed(bad, after), read) => Err(read - bad as usize - after as usize),
+        (DecoderResult::OutputFull, read) => Err(read),
+    }
+}
+
+fn encode_with(
+    encoding: &'static encoding_rs::Encoding,
+    c: char,
+    buf: &mut [u8; 4],
+) -> Option<usize> {
+    let mut encoder = encoding.new_encoder();
+    let mut utf8 = [0u8; 4];
+    let (result, _, written) =
+        encoder.encode_from_utf8_without_replacement(c.encode_utf8(&mut utf8), buf, true);
+    match result {
+        EncoderResult::InputEmpty => Some(written),
+        _ => None,
+    }
+}
+
+/// Encodes `s`, replacing characters which can't be encoded with `?` if `lossy`.
+pub(crate) fn encode(s: &str, out: &mut Vec<u8>, lossy: bool) -> Result<(), ConvertError> {
+    encode_from(&mut Default::default(), s, out, lossy)
+}
+
+/// Encodes `s` following `designations`, which are updated to those at the end.
+pub(crate) fn encode_from(
+    designations: &mut Designations,
+    s: &str,
+    out: &mut Vec<u8>,
+    lossy: bool,
+) -> Result<(), ConvertError> {
+    // The charset of the segment being written, and where its header starts
+    let mut segment: Option<(Charset, usize)> = None;
+
+    for (offset, c) in s.char_indices() {
+        if c == '\t' || c == '\n' {
+            end_segment(out, &mut segment);
+            out.push(c as u8);
+            continue;
+        }
+
+        let (charset, bytes, len) = match Charset::encode_char(c) {
+            Some(x) => x,
+            None if lossy => Charset::encode_char('?').unwrap(),
+            None => {
+                return Err(ConvertError {
+                    convert_from: "UTF-8",
+                    convert_to: "Compound Text",
+                    inner: None,
+                    offset: Some(offset),
+                    charset: None,
+                })
+            }
+        };
+        let bytes = &bytes[..len];
+
+        if let Charset::Big5 | Charset::Utf8 = charset {
+            match segment {
+                Some((x, start))
+                    if x == charset
+                        && (charset == Charset::Utf8
+                            || out.len() + len - (start + 6) <= MAX_EXTENDED_SEGMENT_LENGTH) => {}
+                _ => {
+                    end_segment(out, &mut segment);
+                    segment = Some((charset, out.len()));
+                    if charset == Charset::Utf8 {
+                        out.extend_from_slice(&[ESC, b'%', b'G']);
+                    } else {
+                        // The length is filled by end_segment
+                        out.extend_from_slice(&[ESC, b'%', b'/', b'2', 0x80, 0x80]);
+                        out.extend_from_slice(BIG5_SEGMENT_NAME);
+                        out.push(STX);
+                    }
+                }
+            }
+            out.extend_from_slice(bytes);
+            continue;
+        }
+
+        end_segment(out, &mut segment);
+        let in_gr = charset.is_96() || charset.bytes_per_char() == 2 || bytes[0] >= 0x80;
+        let current = if in_gr {
+            &mut designations.gr
+        } else {
+            &mut designations.gl
+        };
+        if *current != charset {
+            let designation = charset.designation(in_gr).unwrap();
+            let designation_len = if designation[3] == 0 { 3 } else { 4 };
+            out.extend_from_slice(&designation[..designation_len]);
+            *current = charset;
+        }
+        out.extend_from_slice(bytes);
+    }
+
+    end_segment(out, &mut segment);
+    Ok(())
+}
+
+fn end_segment(out: &mut Vec<u8>, segment: &mut Option<(Charset, usize)>) {
+    match segment.take() {
+        Some((Charset::Utf8, _)) => out.extend_from_slice(&[ESC, b'%', b'@']),
+        Some((_, start)) => {
+            let len = out.len() - (start + 6);
+            out[start + 4] = 0x80 | (len / 128) as u8;
+            out[start + 5] = 0x80 | (len % 128) as u8;
+        }
+        None => (),
+    }
+}
+
+// Where decoding failed, and where it resumes after substituting U+FFFD
+struct DecodeError {
+    offset: usize,
+    charset: Option<Charset>,
+    resume: usize,
+    // The input ends in the middle of a character or a sequence
+    truncated: bool,
+}
+
+impl DecodeError {
+    fn new(offset: usize, charset: Option<Charset>, resume: usize) -> Self {
+        DecodeError {
+            offset,
+            charset,
+            resume,
+            truncated: false,
+        }
+    }
+
+    fn truncated(self) -> Self {
+        DecodeError {
+            truncated: true,
+            ..self
+        }
+    }
+}
+
+impl From<DecodeError> for ConvertError {
+    fn from(e: DecodeError) -> Self {
+        ConvertError {
+            convert_from: "Compound Text",
+            convert_to: "UTF-8",
+            inner: None,
+            offset: Some(e.offset),
+            charset: e.charset,
+        }
+    }
+}
+
+/// A part of compound text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Segment<'a> {
+    /// An escape sequence designating `charset` to GL, or to GR if `gr`.
+    Designation { charset: Charset, gr: bool },
+    /// A control sequence starting or ending a direction.
+    Direction(Direction),
+    /// HT, NL or SPACE, which are the same in all charsets.
+    Whitespace(u8),
+    /// Characters in GL or GR bytes as they appear, and the charset designated there.
+    Text { charset: Charset, bytes: &'a [u8] },
+    /// The bytes in an extended segment, or between `ESC % G` and `ESC % @`.
+    Extended { charset: Charset, bytes: &'a [u8] },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    LeftToRight,
+    RightToLeft,
+    /// Ends the innermost direction.
+    End,
+}
+
+/// The charsets designated to GL and GR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Designations {
+    pub gl: Charset,
+    pub gr: Charset,
+}
+
+impl Default for Designations {
+    fn default() -> Self {
+        Designations {
+            gl: Charset::Ascii,
+            gr: Charset::Iso8859(1),
+        }
+    }
+}
+
+impl Designations {
+    fn apply(&mut self, segment: &Segment) {
+        if let Segment::Designation { charset, gr } = *segment {
+            *(if gr { &mut self.gr } else { &mut self.gl }) = charset;
+        }
+    }
+}
+
+/// Decodes `bytes`, substituting U+FFFD for what can't be decoded if `lossy`.
+pub(crate) fn decode(bytes: &[u8], out: &mut String, lossy: bool) -> Result<(), ConvertError> {
+    decode_from(&mut Default::default(), bytes, out, lossy, true).map(|_| ())
+}
+
+/// Decodes `bytes` following `designations`, which are updated to those at the end.
+///
+/// Unless `last`, decoding stops before a character or a sequence cut at the end of `bytes`.
+/// Returns the number of bytes decoded.
+pub(crate) fn decode_from(
+    designations: &mut Designations,
+    bytes: &[u8],
+    out: &mut String,
+    lossy: bool,
+    last: bool,
+) -> Result<usize, ConvertError> {
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let result = parse_segment(bytes, i, designations, last).and_then(|(segment, next)| {
+            designations.apply(&segment);
+            decode_segment(bytes, &segment, out, lossy).map(|_| next)
+        });
+
+        i = match result {
+            Ok(next) => next,
+            Err(e) if e.truncated && !last => return Ok(e.offset),
+            Err(e) if lossy => {
+                out.push(std::char::REPLACEMENT_CHARACTER);
+                e.resume
+            }
+            Err(e) => return Err(e.into()),
+        };
+    }
+
+    Ok(i)
+}
+
+// The offset of `sub` in `bytes`
+fn offset_in(bytes: &[u8], sub: &[u8]) -> usize {
+    sub.as_ptr() as usize - bytes.as_ptr() as usize
+}
+
+fn decode_segment(
+    bytes: &[u8],
+    segment: &Segment,
+    out: &mut String,
+    lossy: bool,
+) -> Result<(), DecodeError> {
+    match *segment {
+        Segment::Designation { .. } | Segment::Direction(_) => Ok(()),
+        Segment::Whitespace(b) => {
+            out.push(b as char);
+            Ok(())
+        }
+        Segment::Text {
+            charset,
+            bytes: text,
+        } => {
+            let offset = offset_in(bytes, text);
+            let result = decode_text(charset, text, offset, out);
+            // A character may continue in the next input
+            result.map_err(
+                |e| match e.offset + charset.bytes_per_char() > bytes.len() {
+                    true => e.truncated(),
+                    false => e,
+                },
+            )
+        }
+        Segment::Extended {
+            charset,
+            bytes: data,
+        } => {
+            let result = match charset {
+                Charset::Big5 => decode_with(encoding_rs::BIG5, data, out, lossy),
+                _ if lossy => {
+                    out.push_str(&String::from_utf8_lossy(data));
+                    Ok(())
+                }
+                _ => match std::str::from_utf8(data) {
+                    Ok(s) => {
+                        out.push_str(s);
+                        Ok(())
+                    }
+                    Err(e) => Err(e.valid_up_to()),
+                },
+            };
+            let start = offset_in(bytes, data);
+            result.map_err(|x| DecodeError::new(start + x, Some(charset), start + data.len()))
+        }
+    }
+}
+
+// Decodes characters from `offset`, which are all in GL or in GR
+fn decode_text(
+    charset: Charset,
+    text: &[u8],
+    offset: usize,
+    out: &mut String,
+) -> Result<(), DecodeError> {
+    let len = charset.bytes_per_char();
+    let mut buf = [0u8; 2];
+
+    for (i, char_bytes) in text.chunks(len).enumerate() {
+        let char_offset = offset + i * len;
+        if char_bytes.len() != len || !check_bytes(charset, char_bytes) {
+            return Err(DecodeError::new(
+                char_offset,
+                Some(charset),
+                char_offset + 1,
+            ));
+        }
+        for (x, y) in buf.iter_mut().zip(char_bytes) {
+            *x = y | 0x80;
+        }
+        if !charset.decode_char(&buf[..len], out) {
+            return Err(DecodeError::new(
+                char_offset,
+                Some(charset),
+                char_offset + len,
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// 94-character sets lack 0xa0 and 0xff
+fn check_bytes(charset: Charset, bytes: &[u8]) -> bool {
+    charset.is_96() || !bytes.iter().any(|&x| x == 0xa0 || x == 0xff)
+}
+
+// Returns the segment at `i`, and the index after it
+fn parse_segment<'a>(
+    bytes: &'a [u8],
+    i: usize,
+    designations: &Designations,
+    last: bool,
+) -> Result<(Segment<'a>, usize), DecodeError> {
+    let b = bytes[i];
+    match b {
+        b'\t' | b'\n' | b' ' => Ok((Segment::Whitespace(b), i + 1)),
+        ESC => parse_escape(bytes, i, last),
+        CSI => parse_direction(bytes, i),
+        0x21..=0x7e | 0xa0..=0xff => {
+            let gr = b >= 0x80;
+            let end = bytes[i..]
+                .iter()
+                .position(|&x| {
+                    if gr {
+                        x < 0xa0
+                    } else {
+                        !(0x21..=0x7e).contains(&x)
+                    }
+                })
+                .map_or(bytes.len(), |x| i + x);
+            let charset = if gr { designations.gr } else { designations.gl };
+            Ok((
+                Segment::Text {
+                    charset,
+                    bytes: &bytes[i..end],
+                },
+                end,
+            ))
+        }
+        _ => Err(DecodeError::new(i, None, i + 1)),
+    }
+}
+
+fn parse_escape(bytes: &[u8], i: usize, last: bool) -> Result<(Segment<'_>, usize), DecodeError> {
+    // An unknown escape sequence is skipped up to its final byte
+    let unknown = || {
+        let end = bytes[i + 1..]
+            .iter()
+            .position(|x| !(0x20..=0x2f).contains(x))
+            .map_or(bytes.len(), |x| i + x + 2);
+        DecodeError::new(i, None, end.min(bytes.len()))
+    };
+    let get = |j: usize| {
+        bytes
+            .get(i + j)
+            .copied()
+            .ok_or_else(|| unknown().truncated())
+    };
+
+    let (charset, gr, len) = match get(1)? {
+        b'(' => (Charset::from_94_final(get(2)?), false, 3),
+        b')' => (Charset::from_94_final(get(2)?), true, 3),
+        b'-' => (Charset::from_96_final(get(2)?), true, 3),
+        b'$' => match get(2)? {
+            b'(' => (Charset::from_94n_final(get(3)?), false, 4),
+            b')' => (Charset::from_94n_final(get(3)?), true, 4),
+            // ESC $ F is the old form of ESC $ ( F
+            f => (Charset::from_94n_final(f), false, 3),
+        },
+        b'%' => return parse_extended(bytes, i, last, unknown),
+        _ => return Err(unknown()),
+    };
+
+    let charset = charset.ok_or_else(unknown)?;
+    Ok((Segment::Designation { charset, gr }, i + len))
+}
+
+// `unknown` is the error for a segment which is not well-formed
+fn parse_extended(
+    bytes: &[u8],
+    i: usize,
+    last: bool,
+    unknown: impl Fn() -> DecodeError,
+) -> Result<(Segment<'_>, usize), DecodeError> {
+    let (charset, data, next) = match bytes.get(i + 2) {
+        Some(b'G') => {
+            let start = i + 3;
+            let end = bytes[start..]
+                .windows(3)
+                .position(|x| x == [ESC, b'%', b'@'])
+                .map(|x| start + x);
+            let end = match end {
+                Some(x) => x,
+                None if last => bytes.len(),
+                None => return Err(unknown().truncated()),
+            };
+            (
+                Charset::Utf8,
+                &bytes[start..end],
+                (end + 3).min(bytes.len()),
+            )
+        }
+        Some(b'/') => {
+            let header = bytes
+                .get(i + 3..i + 6)
+                .ok_or_else(|| unknown().truncated())?;
+            if !(b'0'..=b'4').contains(&header[0]) || header[1] < 0x80 || header[2] < 0x80 {
+                return Err(unknown());
+            }
+            let len = (header[1] - 0x80) as usize * 128 + (header[2] - 0x80) as usize;
+            let end = i + 6 + len;
+            let segment = bytes.get(i + 6..end).ok_or_else(|| unknown().truncated())?;
+            let name_len = segment.iter().position(|&x| x == STX).ok_or_else(unknown)?;
+            match Charset::from_extended_segment_name(&segment[..name_len]) {
+                Some(charset) => (charset, &segment[name_len + 1..], end),
+                None => return Err(DecodeError::new(i, None, end)),
+            }
+        }
+        Some(_) => return Err(unknown()),
+        None => return Err(unknown().truncated()),
+    };
+
+    Ok((
+        Segment::Extended {
+            charset,
+            bytes: data,
+        },
+        next,
+    ))
+}
+
+// CSI 1 ], CSI 2 ] and CSI ] change the direction, which does not affect the text
+fn parse_direction(bytes: &[u8], i: usize) -> Result<(Segment<'_>, usize), DecodeError> {
+    match &bytes[i + 1..] {
+        [b'1', b']', ..] => Ok((Segment::Direction(Direction::LeftToRight), i + 3)),
+        [b'2', b']', ..] => Ok((Segment::Direction(Direction::RightToLeft), i + 3)),
+        [b']', ..] => Ok((Segment::Direction(Direction::End), i + 2)),
+        [] | [b'1'] | [b'2'] => Err(DecodeError::new(i, None, i + 1).truncated()),
+        _ => Err(DecodeError::new(i, None, i + 1)),
+    }
+}
+
+/// Compound text which has been checked to be decodable, like `str` for UTF-8.
+#[derive(PartialEq, Eq, Hash)]
+#[repr(transparent)]
+pub struct CompoundText([u8]);
+
+impl CompoundText {
+    pub fn from_bytes(bytes: &[u8]) -> Result<&CompoundText, ConvertError> {
+        decode(bytes, &mut String::new(), false)?;
+        Ok(Self::from_bytes_unchecked(bytes))
+    }
+
+    fn from_bytes_unchecked(bytes: &[u8]) -> &CompoundText {
+        // CompoundText is a transparent wrapper of [u8]
+        unsafe { &*(bytes as *const [u8] as *const CompoundText) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns an iterator over the designations, directions, characters and extended segments.
+    pub fn segments(&self) -> Segments<'_> {
+        Segments {
+            bytes: &self.0,
+            position: 0,
+            designations: Default::default(),
+        }
+    }
+
+    pub fn to_utf8(&self) -> String {
+        let mut utf8 = String::with_capacity(self.len());
+        // Never fails since it has been checked
+        let _ = decode(&self.0, &mut utf8, true);
+        utf8
+    }
+}
+
+impl AsRef<[u8]> for CompoundText {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl ToOwned for CompoundText {
+    type Owned = CompoundTextBuf;
+
+    fn to_owned(&self) -> CompoundTextBuf {
+        CompoundTextBuf(self.0.to_vec())
+    }
+}
+
+impl fmt::Display for CompoundText {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_utf8())
+    }
+}
+
+impl fmt::Debug for CompoundText {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.to_utf8(), f)
+    }
+}
+
+/// An owned `CompoundText`, like `String` for UTF-8.
+#[derive(Clone, PartialEq, Eq, Hash, Default)]
+pub struct CompoundTextBuf(Vec<u8>);
+
+impl CompoundTextBuf {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<CompoundTextBuf, ConvertError> {
+        CompoundText::from_bytes(&bytes)?;
+        Ok(CompoundTextBuf(bytes))
+    }
+
+    pub fn as_compound_text(&self) -> &CompoundText {
+        CompoundText::from_bytes_unchecked(&self.0)
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+/// Encodes `s`, replacing characters which can't be encoded with `?`.
+impl From<&str> for CompoundTextBuf {
+    fn from(s: &str) -> Self {
+        let mut bytes = Vec::with_capacity(s.len());
+        // Never fails when lossy
+        let _ = encode(s, &mut bytes, true);
+        CompoundTextBuf(bytes)
+    }
+}
+
+impl From<&CompoundText> for CompoundTextBuf {
+    fn from(x: &CompoundText) -> Self {
+        x.to_owned()
+    }
+}
+
+impl Deref for CompoundTextBuf {
+    type Target = CompoundText;
+
+    fn deref(&self) -> &CompoundText {
+        self.as_compound_text()
+    }
+}
+
+impl Borrow<CompoundText> for CompoundTextBuf {
+    fn borrow(&self) -> &CompoundText {
+        self.as_compound_text()
+    }
+}
+
+impl AsRef<CompoundText> for CompoundTextBuf {
+    fn as_ref(&self) -> &CompoundText {
+        self.as_compound_text()
+    }
+}
+
+impl AsRef<[u8]> for CompoundTextBuf {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for CompoundTextBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.as_compound_text(), f)
+    }
+}
+
+impl fmt::Debug for CompoundTextBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_compound_text(), f)
+    }
+}
+
+/// An iterator over the segments of `CompoundText`.
+#[derive(Debug, Clone)]
+pub struct Segments<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    designations: Designations,
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Segment<'a>;
+
+    fn next(&mut self) -> Option<Segment<'a>> {
+        if self.position >= self.bytes.len() {
+            return None;
+        }
+        // Never fails since CompoundText has been checked
+        let (segment, next) =
+            parse_segment(self.bytes, self.position, &self.designations, true).ok()?;
+        self.designations.apply(&segment);
+        self.position = next;
+        Some(segment)
+    }
+}
+
+#[test]
+fn compound_text_test() {
+    fn shuttle(s: &str) -> Vec<u8> {
+        let mut ctext = Vec::new();
+        encode(s, &mut ctext, false).unwrap();
+        let mut utf8 = String::new();
+        decode(&ctext, &mut utf8, false).unwrap();
+        assert_eq!(utf8, s);
+        ctext
+    }
+
+    assert_eq!(shuttle("hello\tworld\n"), b"hello\tworld\n");
+    assert_eq!(shuttle("café"), b"caf\xe9");
+    assert_eq!(shuttle("Žluť"), b"\x1b-B\xaelu\xbb");
+    assert_eq!(shuttle("Ωμέγα"), b"\x1b-F\xd9\xec\xdd\xe3\xe1");
+    assert_eq!(shuttle("Привет"), b"\x1b-L\xbf\xe0\xd8\xd2\xd5\xe2");
+    assert_eq!(shuttle("日本"), b"\x1b$)B\xc6\xfc\xcb\xdc");
+    // NEC row 13 and IBM extensions of EUC-JP are not in JIS X 0208
+    assert_eq!(shuttle("①"), b"\x1b$)A\xa2\xd9");
+    // GBK extensions and PUA in the rows of GB 2312 are not in GB 2312
+    assert!(!shuttle("ⅰ").starts_with(b"\x1b$)A"));
+    assert!(!shuttle("\u{e766}").starts_with(b"\x1b$)A"));
+    assert_eq!(shuttle("髙"), b"\x1b%G\xe9\xab\x99\x1b%@");
+    assert_eq!(shuttle("ｶﾅ"), b"\x1b)I\xb6\xc5");
+    assert_eq!(shuttle("한국"), b"\x1b$)C\xc7\xd1\xb1\xb9");
+    assert_eq!(shuttle("嗎"), b"\x1b%/2\x80\x89big5-0\x02\xb6\xdc");
+    assert_eq!(shuttle("🍣"), b"\x1b%G\xf0\x9f\x8d\xa3\x1b%@");
+    shuttle("hello world!你好世界켐ㅇㄹ貴方元気？☺ Ελληνικά 嗎🍣");
+    shuttle("");
+
+    // Controls other than HT and NL are not allowed anywhere in compound text
+    for s in ["\u{1b}", "\r", "\0", "\u{7f}", "\u{85}"].iter() {
+        assert!(encode(s, &mut Vec::new(), false).is_err());
+    }
+}
+
+#[test]
+fn compound_text_decode_test() {
+    fn decode_str(ctext: &[u8]) -> Result<String, ConvertError> {
+        let mut utf8 = String::new();
+        decode(ctext, &mut utf8, false).map(|_| utf8)
+    }
+
+    // GL designation, the old form, JIS X 0201 and directions
+    assert_eq!(decode_str(b"\x1b$(BF|K\\").unwrap(), "日本");
+    assert_eq!(decode_str(b"\x1b$ADc:C").unwrap(), "你好");
+    assert_eq!(decode_str(b"\x1b(J\\~").unwrap(), "\u{a5}\u{203e}");
+    assert_eq!(decode_str(b"\x9b2]abc\x9b]").unwrap(), "abc");
+    assert_eq!(
+        decode_str(b"\x1b%/0\x80\x8eiso10646-1\x02\xe2\x98\xba").unwrap(),
+        "☺"
+    );
+
+    assert!(decode_str(b"\x1b$)B\xc6").is_err());
+    assert!(decode_str(b"\x1b(Z").is_err());
+    assert!(decode_str(b"\x07").is_err());
+    assert!(decode_str(b"\x1b%/2\x80\x89big5-0\x02\xb6").is_err());
+}
+
+#[test]
+fn segments_test() {
+    let ctext =
+        CompoundText::from_bytes(b"a \x1b$)B\xc6\xfc\x9b2]\x1b%G\xf0\x9f\x8d\xa3\x1b%@\x9b]")
+            .unwrap();
+    let segments = ctext.segments().collect::<Vec<_>>();
+    assert_eq!(
+        segments,
+        [
+            Segment::Text {
+                charset: Charset::Ascii,
+                bytes: b"a"
+            },
+            Segment::Whitespace(b' '),
+            Segment::Designation {
+                charset: Charset::JisX0208,
+                gr: true
+            },
+            Segment::Text {
+                charset: Charset::JisX0208,
+                bytes: b"\xc6\xfc"
+            },
+            Segment::Direction(Direction::RightToLeft),
+            Segment::Extended {
+                charset: Charset::Utf8,
+                bytes: b"\xf0\x9f\x8d\xa3"
+            },
+            Segment::Direction(Direction::End),
+        ]
+    );
+    assert_eq!(ctext.to_string(), "a 日🍣");
+
+    assert!(CompoundText::from_bytes(b"\x1b$)B\xc6").is_err());
+
+    let buf = CompoundTextBuf::from("café 日本");
+    assert_eq!(buf.as_bytes(), b"caf\xe9 \x1b$)B\xc6\xfc\xcb\xdc");
+    assert_eq!(format!("{:?}", buf), "\"café 日本\"");
+    assert_eq!(
+        CompoundTextBuf::from_bytes(buf.clone().into_bytes()).unwrap(),
+        buf
+    );
+}
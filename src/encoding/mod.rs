@@ -0,0 +1,251 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::string::FromUtf8Error;
+
+mod compound_text;
+mod stream;
+
+pub use self::compound_text::{
+    Charset, CompoundText, CompoundTextBuf, Direction, Segment, Segments,
+};
+pub use self::stream::{CompoundTextDecoder, CompoundTextEncoder};
+
+pub fn utf8_to_compound_text(utf8: &[u8]) -> Result<Vec<u8>, ConvertError> {
+    let mut compound_text = Vec::with_capacity(utf8.len());
+    utf8_to_compound_text_into(utf8, &mut compound_text)?;
+    Ok(compound_text)
+}
+
+/// Appends the compound text to `out`, which can be reused across conversions.
+pub fn utf8_to_compound_text_into(utf8: &[u8], out: &mut Vec<u8>) -> Result<(), ConvertError> {
+    let utf8 = std::str::from_utf8(utf8).map_err(|e| ConvertError {
+        convert_from: "UTF-8",
+        convert_to: "Compound Text",
+        inner: None,
+        offset: Some(e.valid_up_to()),
+        charset: None,
+    })?;
+    compound_text::encode(utf8, out, false)
+}
+
+/// Converts to compound text, replacing invalid UTF-8 sequences and characters which can't be
+/// encoded with `?`.
+pub fn utf8_to_compound_text_lossy(utf8: &[u8]) -> Vec<u8> {
+    let mut valid = String::with_capacity(utf8.len());
+    let mut rest = utf8;
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(s) => {
+                valid.push_str(s);
+                break;
+            }
+            Err(e) => {
+                let (s, invalid) = rest.split_at(e.valid_up_to());
+                valid.push_str(std::str::from_utf8(s).unwrap());
+                valid.push('?');
+                rest = &invalid[e.error_len().unwrap_or(invalid.len())..];
+            }
+        }
+    }
+
+    let mut compound_text = Vec::with_capacity(valid.len());
+    compound_text::encode(&valid, &mut compound_text, true).unwrap();
+    compound_text
+}
+
+/// Returns `utf8` as it is if it is also compound text, that is, if it is ASCII.
+pub fn utf8_to_compound_text_cow(utf8: &[u8]) -> Result<Cow<'_, [u8]>, ConvertError> {
+    if is_ascii_text(utf8) {
+        Ok(Cow::Borrowed(utf8))
+    } else {
+        utf8_to_compound_text(utf8).map(Cow::Owned)
+    }
+}
+
+pub fn compound_text_to_utf8(compound_text: &[u8]) -> Result<String, ConvertError> {
+    let mut utf8 = String::with_capacity(compound_text.len());
+    compound_text_to_utf8_into(compound_text, &mut utf8)?;
+    Ok(utf8)
+}
+
+/// Appends the UTF-8 string to `out`, which can be reused across conversions.
+pub fn compound_text_to_utf8_into(
+    compound_text: &[u8],
+    out: &mut String,
+) -> Result<(), ConvertError> {
+    compound_text::decode(compound_text, out, false)
+}
+
+/// Converts to UTF-8, replacing what can't be decoded with U+FFFD.
+pub fn compound_text_to_utf8_lossy(compound_text: &[u8]) -> String {
+    let mut utf8 = String::with_capacity(compound_text.len());
+    compound_text::decode(compound_text, &mut utf8, true).unwrap();
+    utf8
+}
+
+/// Returns `compound_text` as it is if it is also UTF-8, that is, if it is ASCII.
+pub fn compound_text_to_utf8_cow(compound_text: &[u8]) -> Result<Cow<'_, str>, ConvertError> {
+    match std::str::from_utf8(compound_text) {
+        Ok(s) if is_ascii_text(compound_text) => Ok(Cow::Borrowed(s)),
+        _ => compound_text_to_utf8(compound_text).map(Cow::Owned),
+    }
+}
+
+// ASCII without control characters other than HT and NL is the same in UTF-8 and compound text
+fn is_ascii_text(bytes: &[u8]) -> bool {
+    bytes
+        .iter()
+        .all(|&x| matches!(x, b'\t' | b'\n' | 0x20..=0x7e))
+}
+
+/// An encoding used between the server and clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Encoding {
+    #[default]
+    CompoundText,
+    Utf8,
+}
+
+impl Encoding {
+    /// Returns the encoding named by ENCODING_NEGOTIATION, or `None` if not supported.
+    pub fn from_name(name: &[u8]) -> Option<Encoding> {
+        match name {
+            b"COMPOUND_TEXT" => Some(Encoding::CompoundText),
+            b"UTF-8" | b"UTF8_STRING" => Some(Encoding::Utf8),
+            _ => None,
+        }
+    }
+
+    pub fn encode<'a>(&self, s: &'a str) -> Result<Cow<'a, [u8]>, ConvertError> {
+        match self {
+            Encoding::CompoundText => utf8_to_compound_text_cow(s.as_bytes()),
+            Encoding::Utf8 => Ok(Cow::Borrowed(s.as_bytes())),
+        }
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> Result<String, ConvertError> {
+        match self {
+            Encoding::CompoundText => compound_text_to_utf8(bytes),
+            Encoding::Utf8 => String::from_utf8(bytes.to_vec()).map_err(|e| ConvertError {
+                convert_from: "bytes",
+                convert_to: "UTF-8",
+                offset: Some(e.utf8_error().valid_up_to()),
+                charset: None,
+                inner: Some(e),
+            }),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConvertError {
+    convert_from: &'static str,
+    convert_to: &'static str,
+    inner: Option<FromUtf8Error>,
+    offset: Option<usize>,
+    charset: Option<Charset>,
+}
+
+impl ConvertError {
+    /// Returns the byte offset in the input where the conversion failed.
+    pub fn offset(&self) -> Option<usize> {
+        self.offset
+    }
+
+    /// Returns the charset designated where decoding compound text failed.
+    pub fn charset(&self) -> Option<Charset> {
+        self.charset
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        self.inner.as_ref().map(|e| e.as_bytes())
+    }
+
+    pub fn into_bytes(self) -> Option<Vec<u8>> {
+        self.inner.map(|e| e.into_bytes())
+    }
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "failed to convert from {} to {}",
+            self.convert_from, self.convert_to
+        )?;
+        if let Some(offset) = self.offset {
+            write!(f, " at byte {}", offset)?;
+        }
+        if let Some(charset) = self.charset {
+            write!(f, " in {:?}", charset)?;
+        }
+        if let Some(e) = &self.inner {
+            write!(f, ": {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConvertError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.inner.as_ref().map(|e| e as _)
+    }
+}
+
+// Ported from https://github.com/fcitx/xcb-imdkit/blob/bb2f10c4754223bc5afaacab7a6417ee0998e303/test/test_encoding.c
+#[test]
+fn shuttle_test() {
+    fn test_conversion(s: &str) {
+        let result = utf8_to_compound_text(s.as_bytes()).unwrap();
+        let utf8_result = compound_text_to_utf8(&result).unwrap();
+        assert_eq!(utf8_result, s);
+    }
+
+    test_conversion("hello world!你好世界켐ㅇㄹ貴方元気？☺");
+    test_conversion(&String::from_utf8(vec![0xe2, 0x80, 0x93]).unwrap());
+}
+
+#[test]
+fn cow_test() {
+    assert!(matches!(
+        utf8_to_compound_text_cow(b"hello\tworld\n").unwrap(),
+        Cow::Borrowed(_)
+    ));
+    assert!(matches!(
+        utf8_to_compound_text_cow("café".as_bytes()).unwrap(),
+        Cow::Owned(_)
+    ));
+    assert!(matches!(
+        compound_text_to_utf8_cow(b"hello").unwrap(),
+        Cow::Borrowed("hello")
+    ));
+    assert_eq!(compound_text_to_utf8_cow(b"caf\xe9").unwrap(), "café");
+
+    let mut buf = b"a".to_vec();
+    utf8_to_compound_text_into("日本".as_bytes(), &mut buf).unwrap();
+    assert_eq!(buf, b"a\x1b$)B\xc6\xfc\xcb\xdc");
+}
+
+#[test]
+fn error_location_test() {
+    let e = compound_text_to_utf8(b"ab\x1b$)B\xc6\xfc\xc6").unwrap_err();
+    assert_eq!(e.offset(), Some(8));
+    assert_eq!(e.charset(), Some(Charset::JisX0208));
+
+    let e = utf8_to_compound_text(b"ab\xff").unwrap_err();
+    assert_eq!(e.offset(), Some(2));
+    assert_eq!(e.charset(), None);
+
+    let e = utf8_to_compound_text("a\u{1b}".as_bytes()).unwrap_err();
+    assert_eq!(e.offset(), Some(1));
+}
+
+#[test]
+fn lossy_test() {
+    assert_eq!(utf8_to_compound_text_lossy(b"a\xffb\x1bc\rd"), b"a?b?c?d");
+    assert_eq!(
+        compound_text_to_utf8_lossy(b"a\x1b(Zb\x1b$)B\xc6\xfc\xc6\x07c"),
+        "a\u{fffd}b日\u{fffd}\u{fffd}c"
+    );
+}
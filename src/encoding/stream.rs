@@ -0,0 +1,114 @@
+use super::compound_text::{self, Designations};
+use super::ConvertError;
+use std::mem;
+
+/// Decodes compound text given in pieces, keeping the designations between them.
+#[derive(Debug, Clone, Default)]
+pub struct CompoundTextDecoder {
+    designations: Designations,
+    // A character or a sequence cut at the end of the last input
+    pending: Vec<u8>,
+}
+
+impl CompoundTextDecoder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Appends the decoded `bytes` to `out`, and returns the number of characters appended.
+    ///
+    /// A character or a sequence cut at the end of `bytes` is decoded with the next input.
+    /// Pass `last` with the last input. The decoder starts over after it, or after an error.
+    /// The offset of an error counts from the first byte not decoded by the previous calls.
+    pub fn decode(
+        &mut self,
+        bytes: &[u8],
+        out: &mut String,
+        last: bool,
+    ) -> Result<usize, ConvertError> {
+        let out_len = out.len();
+
+        let mut input = mem::take(&mut self.pending);
+        let input = if input.is_empty() {
+            bytes
+        } else {
+            input.extend_from_slice(bytes);
+            &input
+        };
+
+        let decoded =
+            match compound_text::decode_from(&mut self.designations, input, out, false, last) {
+                Ok(x) => x,
+                Err(e) => {
+                    self.reset();
+                    return Err(e);
+                }
+            };
+        if last {
+            self.reset();
+        } else {
+            self.pending = input[decoded..].to_vec();
+        }
+
+        Ok(out[out_len..].chars().count())
+    }
+
+    /// Forgets the designations and the pending input.
+    pub fn reset(&mut self) {
+        *self = Default::default();
+    }
+}
+
+/// Encodes text given in pieces into compound text, keeping the designations between them.
+#[derive(Debug, Clone, Default)]
+pub struct CompoundTextEncoder {
+    designations: Designations,
+}
+
+impl CompoundTextEncoder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Appends the encoded `s` to `out`, and returns the number of characters encoded.
+    pub fn encode(&mut self, s: &str, out: &mut Vec<u8>) -> Result<usize, ConvertError> {
+        compound_text::encode_from(&mut self.designations, s, out, false)?;
+        Ok(s.chars().count())
+    }
+
+    /// Returns to the initial designations, which compound text starts with.
+    pub fn reset(&mut self) {
+        *self = Default::default();
+    }
+}
+
+#[test]
+fn stream_test() {
+    let mut encoder = CompoundTextEncoder::new();
+    let mut ctext = Vec::new();
+    assert_eq!(encoder.encode("日本", &mut ctext).unwrap(), 2);
+    let first_len = ctext.len();
+    assert_eq!(encoder.encode("語 🍣", &mut ctext).unwrap(), 3);
+    // JIS X 0208 is still designated to GR
+    assert_eq!(&ctext[first_len..first_len + 2], b"\xb8\xec");
+
+    // Cut in every place
+    for cut in 0..=ctext.len() {
+        let mut decoder = CompoundTextDecoder::new();
+        let mut utf8 = String::new();
+        let first = decoder.decode(&ctext[..cut], &mut utf8, false).unwrap();
+        let second = decoder.decode(&ctext[cut..], &mut utf8, true).unwrap();
+        assert_eq!(utf8, "日本語 🍣");
+        assert_eq!(first + second, 5);
+    }
+
+    let mut decoder = CompoundTextDecoder::new();
+    let mut utf8 = String::new();
+    assert!(decoder
+        .decode(b"\x1b$)B\xc6\x07", &mut utf8, false)
+        .is_err());
+    // Starts over from ISO 8859-1 after the error
+    utf8.clear();
+    assert_eq!(decoder.decode(b"\xe9", &mut utf8, true).unwrap(), 1);
+    assert_eq!(utf8, "é");
+}
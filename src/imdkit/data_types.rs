@@ -1,5 +1,6 @@
 use super::slice_from_raw;
 use crate::ffi::*;
+use std::ops::Range;
 
 pub type XimTriggerKey = xcb_im_ximtriggerkey_fr_t;
 pub type PreeditAttr = xcb_im_preedit_attr_t;
@@ -11,10 +12,41 @@ pub struct PreeditDrawMessage<'a> {
     pub chg_first: i32,
     pub chg_length: i32,
     pub status: DrawStatus,
+    /// In the encoding of `ImServerRef::get_encoding`, usually the bytes of a `CompoundText`.
     pub preedit_string: &'a [u8],
     pub feedback_array: &'a [Feedback],
 }
 
+impl<'a> PreeditDrawMessage<'a> {
+    pub fn into_owned(self) -> PreeditDrawMessageOwned {
+        self.into()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PreeditDrawMessageOwned {
+    pub caret: i32,
+    pub chg_first: i32,
+    pub chg_length: i32,
+    pub status: DrawStatus,
+    pub preedit_string: Vec<u8>,
+    pub feedback_array: Vec<Feedback>,
+}
+
+impl<'a> From<PreeditDrawMessage<'a>> for PreeditDrawMessageOwned {
+    fn from(x: PreeditDrawMessage<'a>) -> Self {
+        PreeditDrawMessageOwned {
+            caret: x.caret,
+            chg_first: x.chg_first,
+            chg_length: x.chg_length,
+            status: x.status,
+            preedit_string: x.preedit_string.to_vec(),
+            feedback_array: x.feedback_array.to_vec(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct PreeditCaretMessage {
     pub position: i32,
@@ -25,16 +57,41 @@ pub struct PreeditCaretMessage {
 #[derive(Debug, Clone, Copy)]
 pub struct StatusDrawTextMessage<'a> {
     pub status: DrawStatus,
+    /// In the encoding of `ImServerRef::get_encoding`, like `PreeditDrawMessage::preedit_string`.
     pub status_string: &'a [u8],
     pub feedback_array: &'a [Feedback],
 }
 
+impl<'a> StatusDrawTextMessage<'a> {
+    pub fn into_owned(self) -> StatusDrawTextMessageOwned {
+        self.into()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StatusDrawTextMessageOwned {
+    pub status: DrawStatus,
+    pub status_string: Vec<u8>,
+    pub feedback_array: Vec<Feedback>,
+}
+
+impl<'a> From<StatusDrawTextMessage<'a>> for StatusDrawTextMessageOwned {
+    fn from(x: StatusDrawTextMessage<'a>) -> Self {
+        StatusDrawTextMessageOwned {
+            status: x.status,
+            status_string: x.status_string.to_vec(),
+            feedback_array: x.feedback_array.to_vec(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct StatusDrawBitmapMessage {
     pub pixmap_data: xcb::Pixmap,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct XicAttribute<'a> {
     pub attribute_id: u16,
     pub value: &'a [u8],
@@ -49,6 +106,28 @@ impl<'a> From<&'a xcb_im_xicattribute_fr_t> for XicAttribute<'a> {
     }
 }
 
+impl<'a> XicAttribute<'a> {
+    pub fn into_owned(self) -> XicAttributeOwned {
+        self.into()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct XicAttributeOwned {
+    pub attribute_id: u16,
+    pub value: Vec<u8>,
+}
+
+impl<'a> From<XicAttribute<'a>> for XicAttributeOwned {
+    fn from(x: XicAttribute<'a>) -> Self {
+        XicAttributeOwned {
+            attribute_id: x.attribute_id,
+            value: x.value.to_vec(),
+        }
+    }
+}
+
 bitflags! {
     #[derive(Default)]
     pub struct InputStyle: u32 {
@@ -64,8 +143,66 @@ bitflags! {
     }
 }
 
+impl InputStyle {
+    fn preedit_flags() -> InputStyle {
+        InputStyle::PREEDIT_AREA
+            | InputStyle::PREEDIT_CALLBACKS
+            | InputStyle::PREEDIT_POSITION
+            | InputStyle::PREEDIT_NOTHING
+            | InputStyle::PREEDIT_NONE
+    }
+
+    fn status_flags() -> InputStyle {
+        InputStyle::STATUS_AREA
+            | InputStyle::STATUS_CALLBACKS
+            | InputStyle::STATUS_NOTHING
+            | InputStyle::STATUS_NONE
+    }
+
+    /// Returns whether exactly one preedit style and one status style are set.
+    pub fn is_valid(&self) -> bool {
+        (*self & InputStyle::preedit_flags()).bits().count_ones() == 1
+            && (*self & InputStyle::status_flags()).bits().count_ones() == 1
+    }
+
+    /// Classifies the style by its preedit style. Returns `None` if the style is not valid.
+    pub fn kind(&self) -> Option<StyleKind> {
+        if !self.is_valid() {
+            return None;
+        }
+
+        let kind = if self.contains(InputStyle::PREEDIT_CALLBACKS) {
+            StyleKind::OnTheSpot
+        } else if self.contains(InputStyle::PREEDIT_POSITION) {
+            StyleKind::OverTheSpot
+        } else if self.contains(InputStyle::PREEDIT_AREA) {
+            StyleKind::OffTheSpot
+        } else if self.contains(InputStyle::PREEDIT_NOTHING) {
+            StyleKind::Root
+        } else {
+            StyleKind::NoPreedit
+        };
+        Some(kind)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum StyleKind {
+    /// `PREEDIT_CALLBACKS`: the client draws the preedit by PREEDIT_DRAW.
+    OnTheSpot,
+    /// `PREEDIT_POSITION`: the server draws the preedit at the spot location.
+    OverTheSpot,
+    /// `PREEDIT_AREA`: the server draws the preedit in the area given by the client.
+    OffTheSpot,
+    /// `PREEDIT_NOTHING`: the server draws the preedit in its own window.
+    Root,
+    /// `PREEDIT_NONE`: the preedit is not shown.
+    NoPreedit,
+}
+
 bitflags! {
     #[derive(Default)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct DrawStatus: u32 {
         const NO_STRING = 1;
         const NO_FEEDBACK = 2;
@@ -74,6 +211,7 @@ bitflags! {
 
 bitflags! {
     #[derive(Default)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct Feedback: u32 {
         const REVERSE = xcb_im_feedback_t_XCB_XIM_REVERSE;
         const UNDERLINE = xcb_im_feedback_t_XCB_XIM_UNDERLINE;
@@ -87,6 +225,85 @@ bitflags! {
     }
 }
 
+/// Builds a feedback array, which has one `Feedback` per character of the text.
+#[derive(Debug, Clone)]
+pub struct FeedbackBuilder<'a> {
+    text: &'a str,
+    feedback_array: Vec<Feedback>,
+}
+
+impl<'a> FeedbackBuilder<'a> {
+    pub fn new(text: &'a str) -> Self {
+        FeedbackBuilder {
+            text,
+            feedback_array: vec![Feedback::empty(); text.chars().count()],
+        }
+    }
+
+    /// Adds `feedback` to the whole text.
+    pub fn all(mut self, feedback: Feedback) -> Self {
+        for x in &mut self.feedback_array {
+            *x |= feedback;
+        }
+        self
+    }
+
+    /// Adds `feedback` to the characters in the byte range `range` of the text.
+    ///
+    /// # Panics
+    /// Panics if `range` is out of the text or not on char boundaries.
+    pub fn range(mut self, range: Range<usize>, feedback: Feedback) -> Self {
+        assert!(
+            self.text.get(range.clone()).is_some(),
+            "{:?} is not a valid range of {:?}",
+            range,
+            self.text
+        );
+        let first = self.text[..range.start].chars().count();
+        let len = self.text[range].chars().count();
+        for x in &mut self.feedback_array[first..first + len] {
+            *x |= feedback;
+        }
+        self
+    }
+
+    pub fn build(self) -> Vec<Feedback> {
+        self.feedback_array
+    }
+}
+
+bitflags! {
+    /// Core X events which the client forwards to the server.
+    #[derive(Default)]
+    pub struct EventMask: u32 {
+        const KEY_PRESS = xcb::EVENT_MASK_KEY_PRESS;
+        const KEY_RELEASE = xcb::EVENT_MASK_KEY_RELEASE;
+        const BUTTON_PRESS = xcb::EVENT_MASK_BUTTON_PRESS;
+        const BUTTON_RELEASE = xcb::EVENT_MASK_BUTTON_RELEASE;
+        const ENTER_WINDOW = xcb::EVENT_MASK_ENTER_WINDOW;
+        const LEAVE_WINDOW = xcb::EVENT_MASK_LEAVE_WINDOW;
+        const POINTER_MOTION = xcb::EVENT_MASK_POINTER_MOTION;
+        const POINTER_MOTION_HINT = xcb::EVENT_MASK_POINTER_MOTION_HINT;
+        const BUTTON_1_MOTION = xcb::EVENT_MASK_BUTTON_1_MOTION;
+        const BUTTON_2_MOTION = xcb::EVENT_MASK_BUTTON_2_MOTION;
+        const BUTTON_3_MOTION = xcb::EVENT_MASK_BUTTON_3_MOTION;
+        const BUTTON_4_MOTION = xcb::EVENT_MASK_BUTTON_4_MOTION;
+        const BUTTON_5_MOTION = xcb::EVENT_MASK_BUTTON_5_MOTION;
+        const BUTTON_MOTION = xcb::EVENT_MASK_BUTTON_MOTION;
+        const KEYMAP_STATE = xcb::EVENT_MASK_KEYMAP_STATE;
+        const EXPOSURE = xcb::EVENT_MASK_EXPOSURE;
+        const VISIBILITY_CHANGE = xcb::EVENT_MASK_VISIBILITY_CHANGE;
+        const STRUCTURE_NOTIFY = xcb::EVENT_MASK_STRUCTURE_NOTIFY;
+        const RESIZE_REDIRECT = xcb::EVENT_MASK_RESIZE_REDIRECT;
+        const SUBSTRUCTURE_NOTIFY = xcb::EVENT_MASK_SUBSTRUCTURE_NOTIFY;
+        const SUBSTRUCTURE_REDIRECT = xcb::EVENT_MASK_SUBSTRUCTURE_REDIRECT;
+        const FOCUS_CHANGE = xcb::EVENT_MASK_FOCUS_CHANGE;
+        const PROPERTY_CHANGE = xcb::EVENT_MASK_PROPERTY_CHANGE;
+        const COLOR_MAP_CHANGE = xcb::EVENT_MASK_COLOR_MAP_CHANGE;
+        const OWNER_GRAB_BUTTON = xcb::EVENT_MASK_OWNER_GRAB_BUTTON;
+    }
+}
+
 bitflags! {
     #[derive(Default)]
     pub struct ForwardEventFlag: u16 {
@@ -103,6 +320,60 @@ bitflags! {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Rectangle {
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl From<xcb::ffi::xproto::xcb_rectangle_t> for Rectangle {
+    fn from(x: xcb::ffi::xproto::xcb_rectangle_t) -> Self {
+        Rectangle {
+            x: x.x,
+            y: x.y,
+            width: x.width,
+            height: x.height,
+        }
+    }
+}
+
+/// Areas of the preedit and the status set by the client.
+///
+/// `XNArea` is used if set, otherwise `XNAreaNeeded`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Geometry {
+    pub preedit_area: Option<Rectangle>,
+    pub status_area: Option<Rectangle>,
+}
+
+bitflags! {
+    /// Attributes in `PreeditAttr` or `StatusAttr` set by the client.
+    #[derive(Default)]
+    pub struct AttrMask: u32 {
+        const AREA = _xcb_im_attr_mask_t_XCB_XIM_XNArea_MASK;
+        const AREA_NEEDED = _xcb_im_attr_mask_t_XCB_XIM_XNAreaNeeded_MASK;
+        const SPOT_LOCATION = _xcb_im_attr_mask_t_XCB_XIM_XNSpotLocation_MASK;
+        const COLORMAP = _xcb_im_attr_mask_t_XCB_XIM_XNColormap_MASK;
+        const FOREGROUND = _xcb_im_attr_mask_t_XCB_XIM_XNForeground_MASK;
+        const BACKGROUND = _xcb_im_attr_mask_t_XCB_XIM_XNBackground_MASK;
+        const BACKGROUND_PIXMAP = _xcb_im_attr_mask_t_XCB_XIM_XNBackgroundPixmap_MASK;
+        const LINE_SPACE = _xcb_im_attr_mask_t_XCB_XIM_XNLineSpace_MASK;
+    }
+}
+
+bitflags! {
+    /// Protocol state of an input context tracked by `ImServer`.
+    #[derive(Default)]
+    pub struct IcState: u32 {
+        const FOCUSED = 1;
+        const PREEDIT_ACTIVE = 2;
+        const STATUS_ACTIVE = 4;
+        const SYNC_PENDING = 8;
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum CaretDirection {
     ForwardChar = 0,
@@ -170,3 +441,113 @@ impl From<TriggerNotifyFlag> for u32 {
         }
     }
 }
+
+/// Error codes of XIM_ERROR defined in the XIM protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum XimErrorCode {
+    BadAlloc,
+    BadStyle,
+    BadClientWindow,
+    BadFocusWindow,
+    BadArea,
+    BadSpotLocation,
+    BadColormap,
+    BadAtom,
+    BadPixel,
+    BadPixmap,
+    BadName,
+    BadCursor,
+    BadProtocol,
+    BadForeground,
+    BadBackground,
+    LocaleNotSupported,
+    BadSomething,
+    Other(u16),
+}
+
+impl From<u16> for XimErrorCode {
+    fn from(x: u16) -> Self {
+        use XimErrorCode::*;
+        match x {
+            1 => BadAlloc,
+            2 => BadStyle,
+            3 => BadClientWindow,
+            4 => BadFocusWindow,
+            5 => BadArea,
+            6 => BadSpotLocation,
+            7 => BadColormap,
+            8 => BadAtom,
+            9 => BadPixel,
+            10 => BadPixmap,
+            11 => BadName,
+            12 => BadCursor,
+            13 => BadProtocol,
+            14 => BadForeground,
+            15 => BadBackground,
+            16 => LocaleNotSupported,
+            999 => BadSomething,
+            x => Other(x),
+        }
+    }
+}
+
+impl From<XimErrorCode> for u16 {
+    fn from(x: XimErrorCode) -> Self {
+        use XimErrorCode::*;
+        match x {
+            BadAlloc => 1,
+            BadStyle => 2,
+            BadClientWindow => 3,
+            BadFocusWindow => 4,
+            BadArea => 5,
+            BadSpotLocation => 6,
+            BadColormap => 7,
+            BadAtom => 8,
+            BadPixel => 9,
+            BadPixmap => 10,
+            BadName => 11,
+            BadCursor => 12,
+            BadProtocol => 13,
+            BadForeground => 14,
+            BadBackground => 15,
+            LocaleNotSupported => 16,
+            BadSomething => 999,
+            Other(x) => x,
+        }
+    }
+}
+
+#[test]
+fn style_kind_test() {
+    let over_the_spot = InputStyle::PREEDIT_POSITION | InputStyle::STATUS_AREA;
+    assert!(over_the_spot.is_valid());
+    assert_eq!(over_the_spot.kind(), Some(StyleKind::OverTheSpot));
+
+    let root = InputStyle::PREEDIT_NOTHING | InputStyle::STATUS_NONE;
+    assert_eq!(root.kind(), Some(StyleKind::Root));
+
+    let no_status = InputStyle::PREEDIT_CALLBACKS;
+    assert!(!no_status.is_valid());
+    assert_eq!(no_status.kind(), None);
+
+    let two_preedits =
+        InputStyle::PREEDIT_CALLBACKS | InputStyle::PREEDIT_AREA | InputStyle::STATUS_NOTHING;
+    assert_eq!(two_preedits.kind(), None);
+}
+
+#[test]
+fn feedback_builder_test() {
+    let text = "aにほんご";
+    let feedback_array = FeedbackBuilder::new(text)
+        .all(Feedback::UNDERLINE)
+        .range(1..7, Feedback::REVERSE)
+        .build();
+    let underline = Feedback::UNDERLINE;
+    let reverse = Feedback::UNDERLINE | Feedback::REVERSE;
+    assert_eq!(
+        feedback_array,
+        [underline, reverse, reverse, underline, underline]
+    );
+
+    assert!(FeedbackBuilder::new("").build().is_empty());
+}
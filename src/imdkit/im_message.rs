@@ -1,7 +1,9 @@
 use super::data_types::*;
-use super::{slice_from_raw, ImClient, ImServerRef, InputContext};
+use super::{slice_from_raw, ImClient, ImServerRef, InputContext, PreeditResetAction};
+use crate::encoding::CompoundTextBuf;
 use crate::ffi::*;
-use std::mem;
+use std::fmt;
+use std::mem::{self, ManuallyDrop};
 use std::os::raw::c_void;
 use std::slice;
 
@@ -49,7 +51,8 @@ pub trait ImMessageHandler {
         _client: &ImClient,
         _ic: &InputContext,
         _frame: &GetIcValuesMessage,
-    ) {
+    ) -> GetIcValuesReplyMessage {
+        Default::default()
     }
 
     fn handle_set_ic_focus(
@@ -74,12 +77,12 @@ pub trait ImMessageHandler {
 
     fn handle_reset_ic(
         &mut self,
-        _im: &ImServerRef,
+        im: &ImServerRef,
         _client: &ImClient,
-        _ic: &InputContext,
+        ic: &InputContext,
         _frame: &ResetIcMessage,
     ) -> ResetIcReplyMessage {
-        Default::default()
+        im.reset_preedit(ic, PreeditResetAction::Return)
     }
 
     fn handle_forward_event(
@@ -88,7 +91,7 @@ pub trait ImMessageHandler {
         _client: &ImClient,
         _ic: &InputContext,
         _frame: &ForwardEventMessage,
-        _key_event: &xcb::KeyPressEvent,
+        _event: &ForwardedEvent,
     ) {
     }
 
@@ -175,12 +178,23 @@ pub(crate) fn handle_callback(
                 args.ic.unwrap(),
                 &(&*(args.frame as *const xcb_im_set_ic_values_fr_t)).into(),
             ),
-            (XCB_XIM_GET_IC_VALUES, _) => handler.handle_get_ic_values(
-                im,
-                args.client.unwrap(),
-                args.ic.unwrap(),
-                &(&*(args.frame as *const xcb_im_get_ic_values_fr_t)).into(),
-            ),
+            (XCB_XIM_GET_IC_VALUES, _) => {
+                let reply = handler.handle_get_ic_values(
+                    im,
+                    args.client.unwrap(),
+                    args.ic.unwrap(),
+                    &(&*(args.frame as *const xcb_im_get_ic_values_fr_t)).into(),
+                );
+                if !reply.ic_attributes.is_empty() {
+                    let result = set_get_ic_values_reply(
+                        &mut *(args.arg as *mut xcb_im_get_ic_values_reply_fr_t),
+                        &reply,
+                    );
+                    if let (true, Err(e)) = (cfg!(debug_assertions), result) {
+                        panic!("failed to set the GET_IC_VALUES reply: {}", e)
+                    }
+                }
+            }
             (XCB_XIM_SET_IC_FOCUS, _) => handler.handle_set_ic_focus(
                 im,
                 args.client.unwrap(),
@@ -216,17 +230,15 @@ pub(crate) fn handle_callback(
                 }
             }
             (XCB_XIM_FORWARD_EVENT, _) => {
-                let key_event = xcb::Event {
-                    ptr: args.arg as *mut xcb::ffi::xcb_key_press_event_t,
-                };
+                let event =
+                    ForwardedEvent::from_raw(args.arg as *mut xcb::ffi::xcb_generic_event_t);
                 handler.handle_forward_event(
                     im,
                     args.client.unwrap(),
                     args.ic.unwrap(),
                     &(&*(args.frame as *const xcb_im_forward_event_fr_t)).into(),
-                    &key_event,
+                    &event,
                 );
-                mem::forget(key_event); // do not free
             }
             (XCB_XIM_EXTENSION, XCB_XIM_EXT_FORWARD_KEYEVENT) => {
                 let key_event = xcb::Event {
@@ -274,7 +286,80 @@ pub(crate) fn handle_callback(
     };
 }
 
-#[derive(Debug, Clone)]
+// Merges the reply into the attributes filled in by xcb-imdkit, which are left as they are if
+// the reply can't be set.
+// The buffers are allocated by malloc because xcb-imdkit frees them after sending the reply.
+unsafe fn set_get_ic_values_reply(
+    reply_frame: &mut xcb_im_get_ic_values_reply_fr_t,
+    reply: &GetIcValuesReplyMessage,
+) -> Result<(), &'static str> {
+    if reply
+        .ic_attributes
+        .iter()
+        .any(|x| x.value.len() > u16::MAX as usize)
+    {
+        return Err("an attribute value is longer than 65535 bytes");
+    }
+
+    let filled = slice_from_raw(
+        reply_frame.ic_attribute.items,
+        reply_frame.ic_attribute.size as usize,
+    );
+    let is_replaced = |x: &xcb_im_xicattribute_fr_t| {
+        reply
+            .ic_attributes
+            .iter()
+            .any(|y| y.attribute_id == x.attribute_ID)
+    };
+
+    // Allocate everything before changing the frame
+    let len = filled.iter().filter(|x| !is_replaced(x)).count() + reply.ic_attributes.len();
+    let allocated = libc::calloc(len, mem::size_of::<xcb_im_xicattribute_fr_t>())
+        as *mut xcb_im_xicattribute_fr_t;
+    let values = reply
+        .ic_attributes
+        .iter()
+        .map(|x| libc::calloc(x.value.len() + 1, mem::size_of::<u8>()) as *mut u8)
+        .collect::<Vec<_>>();
+    if allocated.is_null() || values.iter().any(|x| x.is_null()) {
+        libc::free(allocated as *mut c_void);
+        for &value in &values {
+            libc::free(value as *mut c_void);
+        }
+        return Err("failed to allocate memory");
+    }
+    let items = slice::from_raw_parts_mut(allocated, len);
+
+    let mut i = 0;
+    for x in filled {
+        if is_replaced(x) {
+            libc::free(x.value as *mut c_void);
+        } else {
+            items[i] = xcb_im_xicattribute_fr_t {
+                attribute_ID: x.attribute_ID,
+                value_length: x.value_length,
+                value: x.value,
+            };
+            i += 1;
+        }
+    }
+    for (x, &value) in reply.ic_attributes.iter().zip(&values) {
+        slice::from_raw_parts_mut(value, x.value.len()).copy_from_slice(&x.value);
+        items[i] = xcb_im_xicattribute_fr_t {
+            attribute_ID: x.attribute_id,
+            value_length: x.value.len() as u16,
+            value,
+        };
+        i += 1;
+    }
+
+    libc::free(reply_frame.ic_attribute.items as *mut c_void);
+    reply_frame.ic_attribute.size = len as u32;
+    reply_frame.ic_attribute.items = allocated; // freed by xcb-imdkit
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ConnectMessage<'a> {
     pub byte_order: u8,
     pub client_major_protocol_version: u16,
@@ -303,7 +388,37 @@ impl<'a> From<&'a xcb_im_connect_fr_t> for ConnectMessage<'a> {
     }
 }
 
-#[derive(Debug, Clone)]
+impl<'a> ConnectMessage<'a> {
+    pub fn into_owned(self) -> ConnectMessageOwned {
+        self.into()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectMessageOwned {
+    pub byte_order: u8,
+    pub client_major_protocol_version: u16,
+    pub client_minor_protocol_version: u16,
+    pub client_auth_protocol_names: Vec<Vec<u8>>,
+}
+
+impl<'a> From<ConnectMessage<'a>> for ConnectMessageOwned {
+    fn from(x: ConnectMessage<'a>) -> Self {
+        ConnectMessageOwned {
+            byte_order: x.byte_order,
+            client_major_protocol_version: x.client_major_protocol_version,
+            client_minor_protocol_version: x.client_minor_protocol_version,
+            client_auth_protocol_names: x
+                .client_auth_protocol_names
+                .into_iter()
+                .map(|x| x.to_vec())
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OpenMessage<'a> {
     pub locale_name: &'a [u8],
 }
@@ -316,9 +431,29 @@ impl<'a> From<&'a xcb_im_open_fr_t> for OpenMessage<'a> {
     }
 }
 
+impl<'a> OpenMessage<'a> {
+    pub fn into_owned(self) -> OpenMessageOwned {
+        self.into()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OpenMessageOwned {
+    pub locale_name: Vec<u8>,
+}
+
+impl<'a> From<OpenMessage<'a>> for OpenMessageOwned {
+    fn from(x: OpenMessage<'a>) -> Self {
+        OpenMessageOwned {
+            locale_name: x.locale_name.to_vec(),
+        }
+    }
+}
+
 pub type CloseMessage = xcb_im_close_fr_t;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CreateIcMessage<'a> {
     pub input_method_id: u16,
     pub ic_attributes: Vec<XicAttribute<'a>>,
@@ -338,9 +473,31 @@ impl<'a> From<&'a xcb_im_create_ic_fr_t> for CreateIcMessage<'a> {
     }
 }
 
+impl<'a> CreateIcMessage<'a> {
+    pub fn into_owned(self) -> CreateIcMessageOwned {
+        self.into()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CreateIcMessageOwned {
+    pub input_method_id: u16,
+    pub ic_attributes: Vec<XicAttributeOwned>,
+}
+
+impl<'a> From<CreateIcMessage<'a>> for CreateIcMessageOwned {
+    fn from(x: CreateIcMessage<'a>) -> Self {
+        CreateIcMessageOwned {
+            input_method_id: x.input_method_id,
+            ic_attributes: x.ic_attributes.into_iter().map(|x| x.into()).collect(),
+        }
+    }
+}
+
 pub type CreateIcReplyMessage = xcb_im_create_ic_reply_fr_t;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SetIcValuesMessage<'a> {
     pub input_method_id: u16,
     pub input_context_id: u16,
@@ -362,7 +519,31 @@ impl<'a> From<&'a xcb_im_set_ic_values_fr_t> for SetIcValuesMessage<'a> {
     }
 }
 
-#[derive(Debug, Clone)]
+impl<'a> SetIcValuesMessage<'a> {
+    pub fn into_owned(self) -> SetIcValuesMessageOwned {
+        self.into()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SetIcValuesMessageOwned {
+    pub input_method_id: u16,
+    pub input_context_id: u16,
+    pub ic_attributes: Vec<XicAttributeOwned>,
+}
+
+impl<'a> From<SetIcValuesMessage<'a>> for SetIcValuesMessageOwned {
+    fn from(x: SetIcValuesMessage<'a>) -> Self {
+        SetIcValuesMessageOwned {
+            input_method_id: x.input_method_id,
+            input_context_id: x.input_context_id,
+            ic_attributes: x.ic_attributes.into_iter().map(|x| x.into()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct GetIcValuesMessage<'a> {
     pub input_method_id: u16,
     pub input_context_id: u16,
@@ -381,6 +562,39 @@ impl<'a> From<&'a xcb_im_get_ic_values_fr_t> for GetIcValuesMessage<'a> {
     }
 }
 
+impl<'a> GetIcValuesMessage<'a> {
+    pub fn into_owned(self) -> GetIcValuesMessageOwned {
+        self.into()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GetIcValuesMessageOwned {
+    pub input_method_id: u16,
+    pub input_context_id: u16,
+    pub ic_attribute_id: Vec<u16>,
+}
+
+impl<'a> From<GetIcValuesMessage<'a>> for GetIcValuesMessageOwned {
+    fn from(x: GetIcValuesMessage<'a>) -> Self {
+        GetIcValuesMessageOwned {
+            input_method_id: x.input_method_id,
+            input_context_id: x.input_context_id,
+            ic_attribute_id: x.ic_attribute_id.to_vec(),
+        }
+    }
+}
+
+/// Attributes answered to GET_IC_VALUES in addition to the ones xcb-imdkit fills in.
+///
+/// An attribute with the same ID as one filled in by xcb-imdkit replaces it.
+/// If a value is longer than 65535 bytes, only the attributes filled in by xcb-imdkit are sent.
+#[derive(Debug, Clone, Default)]
+pub struct GetIcValuesReplyMessage {
+    pub ic_attributes: Vec<XicAttributeOwned>,
+}
+
 pub type SetIcFocusMessage = xcb_im_set_ic_focus_fr_t;
 
 pub type UnsetIcFocusMessage = xcb_im_unset_ic_focus_fr_t;
@@ -389,9 +603,18 @@ pub type ResetIcMessage = xcb_im_reset_ic_fr_t;
 
 #[derive(Debug, Clone, Default)]
 pub struct ResetIcReplyMessage {
+    /// In the encoding of `ImServerRef::get_encoding`.
     pub preedit_string: Vec<u8>,
 }
 
+impl From<CompoundTextBuf> for ResetIcReplyMessage {
+    fn from(x: CompoundTextBuf) -> Self {
+        ResetIcReplyMessage {
+            preedit_string: x.into_bytes(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ForwardEventMessage {
     pub input_method_id: u16,
@@ -412,6 +635,57 @@ impl<'a> From<&'a xcb_im_forward_event_fr_t> for ForwardEventMessage {
     }
 }
 
+/// An event forwarded by FORWARD_EVENT.
+///
+/// The event is owned by xcb-imdkit and is valid only during the handler.
+pub struct ForwardedEvent {
+    event: ManuallyDrop<xcb::GenericEvent>,
+    // The same event as a key event if it is KeyPress or KeyRelease
+    key_event: Option<ManuallyDrop<xcb::KeyPressEvent>>,
+}
+
+impl ForwardedEvent {
+    unsafe fn from_raw(ptr: *mut xcb::ffi::xcb_generic_event_t) -> Self {
+        let event = ManuallyDrop::new(xcb::Event { ptr });
+        let key_event = match event.response_type() & 0x7f {
+            xcb::KEY_PRESS | xcb::KEY_RELEASE => {
+                Some(ManuallyDrop::new(xcb::Event { ptr: ptr as *mut _ }))
+            }
+            _ => None,
+        };
+        ForwardedEvent { event, key_event }
+    }
+
+    /// Returns the response type without the bit indicating that the event was sent by SendEvent.
+    pub fn response_type(&self) -> u8 {
+        self.event.response_type() & 0x7f
+    }
+
+    pub fn as_generic_event(&self) -> &xcb::GenericEvent {
+        &self.event
+    }
+
+    pub fn key_press(&self) -> Option<&xcb::KeyPressEvent> {
+        self.key_event
+            .as_deref()
+            .filter(|_| self.response_type() == xcb::KEY_PRESS)
+    }
+
+    pub fn key_release(&self) -> Option<&xcb::KeyReleaseEvent> {
+        self.key_event
+            .as_deref()
+            .filter(|_| self.response_type() == xcb::KEY_RELEASE)
+    }
+}
+
+impl fmt::Debug for ForwardedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ForwardedEvent")
+            .field("response_type", &self.event.response_type())
+            .finish()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ExtForwardKeyeventMessage {
     pub input_method_id: u16,
@@ -1,14 +1,19 @@
+use super::preedit::{self, Preedit};
 use super::*;
+use crate::encoding::{CompoundText, ConvertError, Encoding};
 use crate::ffi;
 use std::borrow::Borrow;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
 use std::ffi::CStr;
 use std::fmt;
 use std::marker::PhantomData;
 use std::mem;
+use std::ops::Range;
 use std::os::raw::{c_char, c_void};
 use std::ptr::NonNull;
+use std::time::{Duration, Instant};
 use xcb;
 
 // Note: Can we mark ImServer as Send?
@@ -25,6 +30,10 @@ pub struct ImServer<'a> {
 #[derive(PartialEq, Eq, Hash)]
 pub struct ImServerRef(NonNull<ImServerData>);
 
+/// A committed string in the encoding of `ImServerRef::get_encoding`.
+///
+/// The strings stay as bytes because the encoding is UTF-8 if the server offers only UTF-8.
+/// Otherwise they are compound text, which can be built with `CompoundTextBuf`.
 #[derive(Debug, Clone, Copy)]
 pub enum CommittedString<'a> {
     KeySym(u32),
@@ -32,10 +41,186 @@ pub enum CommittedString<'a> {
     Both(u32, &'a [u8]),
 }
 
+impl<'a> From<&'a CompoundText> for CommittedString<'a> {
+    fn from(x: &'a CompoundText) -> Self {
+        CommittedString::Chars(x.as_bytes())
+    }
+}
+
+/// A committed string before conversion to the encoding of the client.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Commit {
+    KeySym(u32),
+    Text(String),
+    Both(u32, String),
+}
+
+/// The status shown by `ImServerRef::set_status`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Status {
+    /// `spans` are byte ranges of `text` with their feedback.
+    Text {
+        text: String,
+        spans: Vec<(Range<usize>, Feedback)>,
+    },
+    Bitmap(xcb::Pixmap),
+    Hidden,
+}
+
+/// What `ImServerRef::reset_preedit` does with the preedit set by `set_preedit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PreeditResetAction {
+    /// Returns the preedit to the client by RESET_IC_REPLY and clears it.
+    Return,
+    /// Commits the preedit and clears it.
+    Commit,
+    /// Clears the preedit.
+    Discard,
+    /// Leaves the preedit as it is.
+    Keep,
+}
+
+/// What `set_preedit` does when the preedit exceeds the length the client accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum PreeditLengthPolicy {
+    /// Drops the characters that do not fit.
+    #[default]
+    Truncate,
+    /// Returns `PreeditError::TooLong` without sending anything.
+    Error,
+}
+
+#[derive(Debug)]
+pub enum PreeditError {
+    Convert(ConvertError),
+    /// The preedit is longer than `max_length` bytes the client accepts.
+    TooLong {
+        max_length: usize,
+    },
+}
+
+impl From<ConvertError> for PreeditError {
+    fn from(x: ConvertError) -> Self {
+        PreeditError::Convert(x)
+    }
+}
+
+impl fmt::Display for PreeditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PreeditError::Convert(e) => e.fmt(f),
+            PreeditError::TooLong { max_length } => write!(
+                f,
+                "the preedit exceeds the maximum length of {} bytes",
+                max_length
+            ),
+        }
+    }
+}
+
+impl Error for PreeditError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PreeditError::Convert(e) => Some(e),
+            PreeditError::TooLong { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DrawError {
+    /// The string can't be decoded from the encoding of the client to count its characters.
+    Convert(ConvertError),
+    /// `feedback_array` does not have one `Feedback` per character of the string.
+    FeedbackLength { chars: usize, feedbacks: usize },
+}
+
+impl From<ConvertError> for DrawError {
+    fn from(x: ConvertError) -> Self {
+        DrawError::Convert(x)
+    }
+}
+
+impl fmt::Display for DrawError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DrawError::Convert(e) => e.fmt(f),
+            DrawError::FeedbackLength { chars, feedbacks } => write!(
+                f,
+                "{} feedbacks are given for {} characters",
+                feedbacks, chars
+            ),
+        }
+    }
+}
+
+impl Error for DrawError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DrawError::Convert(e) => Some(e),
+            DrawError::FeedbackLength { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SyncResult {
+    Replied,
+    TimedOut,
+}
+
+type SyncCallback = Box<dyn FnOnce(&ImServerRef, &InputContext, SyncResult)>;
+
+type GeometryCallback = Box<dyn FnOnce(&ImServerRef, &InputContext, Geometry)>;
+
+// XNArea and XNAreaNeeded of the preedit and the status
+type AreaAttrs = [(AttrMask, Rectangle, Rectangle); 2];
+
+fn area_attrs(ic: &InputContext) -> AreaAttrs {
+    let mask = AttrMask::AREA | AttrMask::AREA_NEEDED;
+    let preedit_attr = ic.get_preedit_attr();
+    let status_attr = ic.get_status_attr();
+    [
+        (
+            ic.get_preedit_attr_mask() & mask,
+            preedit_attr.area.into(),
+            preedit_attr.area_needed.into(),
+        ),
+        (
+            ic.get_status_attr_mask() & mask,
+            status_attr.area.into(),
+            status_attr.area_needed.into(),
+        ),
+    ]
+}
+
+struct PendingSync {
+    deadline: Instant,
+    on_done: SyncCallback,
+}
+
 struct ImServerData {
     im: Option<NonNull<ffi::xcb_im_t>>,
     handler: RefCell<Box<dyn ImMessageHandler>>,
-    input_contexts: HashMap<NonNull<ffi::xcb_im_input_context_t>, InputContext>,
+    input_contexts: HashMap<NonNull<ffi::xcb_im_input_context_t>, InputContextData>,
+    preedit_length_policy: PreeditLengthPolicy,
+    // The encoding of strings sent to clients
+    encoding: Encoding,
+}
+
+struct InputContextData {
+    ic: InputContext,
+    state: IcState,
+    // XIM_SYNC sent by `sync` and not replied or timed out yet, in order
+    pending_syncs: VecDeque<PendingSync>,
+    // Waiting for SET_IC_VALUES with the area after XIM_GEOMETRY
+    pending_geometry: Vec<GeometryCallback>,
+    // The areas set by the client so far, to find SET_IC_VALUES changing them
+    areas: AreaAttrs,
+    // The preedit set by set_preedit
+    preedit: Preedit,
+    // The maximum bytes of the preedit answered by PREEDIT_START_REPLY. None if unlimited.
+    max_preedit_length: Option<usize>,
 }
 
 impl<'a> ImServer<'a> {
@@ -49,7 +234,7 @@ impl<'a> ImServer<'a> {
         on_keys_list: &[XimTriggerKey],
         off_keys_list: &[XimTriggerKey],
         encoding_list: impl IntoIterator<Item = E>,
-        event_mask: u32,
+        event_mask: EventMask,
         handler: impl ImMessageHandler + 'a,
     ) -> Self
     where
@@ -67,10 +252,20 @@ impl<'a> ImServer<'a> {
             nKeys: off_keys_list.len() as u16,
             keys: off_keys_list.as_ptr() as *mut ffi::xcb_im_ximtriggerkey_fr_t,
         };
+        let mut utf8_only = true;
         let encoding_list_ptrs = encoding_list
             .into_iter()
-            .map(|s| s.as_ref().as_ptr() as *mut c_char)
+            .map(|s| {
+                let s = s.as_ref();
+                utf8_only &= Encoding::from_name(s.to_bytes()) == Some(Encoding::Utf8);
+                s.as_ptr() as *mut c_char
+            })
             .collect::<Vec<_>>();
+        let encoding = if utf8_only && !encoding_list_ptrs.is_empty() {
+            Encoding::Utf8
+        } else {
+            Encoding::CompoundText
+        };
         let encoding_list = ffi::xcb_im_encodings_t {
             nEncodings: encoding_list_ptrs.len() as u16,
             encodings: encoding_list_ptrs.as_ptr() as *mut ffi::xcb_im_encoding_t,
@@ -92,6 +287,8 @@ impl<'a> ImServer<'a> {
             im: None,
             handler: handler_cell,
             input_contexts: Default::default(),
+            preedit_length_policy: Default::default(),
+            encoding,
         }));
 
         let im = NonNull::new(unsafe {
@@ -105,7 +302,7 @@ impl<'a> ImServer<'a> {
                 &on_keys_list,
                 &off_keys_list,
                 &encoding_list,
-                event_mask,
+                event_mask.bits(),
                 Some(im_callback),
                 data_ptr as *mut c_void,
             )
@@ -135,12 +332,18 @@ impl<'a> ImServer<'a> {
     }
 
     pub fn filter_event(&mut self, event: &xcb::GenericEvent) -> bool {
-        unsafe { ffi::xcb_im_filter_event(self.as_ref().get_im_ptr(), event.ptr) }
+        let result = unsafe { ffi::xcb_im_filter_event(self.as_ref().get_im_ptr(), event.ptr) };
+        self.as_ref().check_sync_timeouts();
+        result
     }
 
     pub fn close_on_drop(&mut self, enabled: bool) {
         self.close_on_drop = enabled;
     }
+
+    pub fn preedit_length_policy(&mut self, policy: PreeditLengthPolicy) {
+        self.as_ref().get_data().preedit_length_policy = policy;
+    }
 }
 
 extern "C" fn im_callback(
@@ -181,17 +384,74 @@ extern "C" fn im_callback(
     // Maintain alive ICs
     let destroyed_ic = match (raw_args.major_opcode as u32, ic_ptr_opt) {
         (ffi::XCB_XIM_CREATE_IC, Some(ic)) => {
-            data_cell.input_contexts.insert(ic, InputContext(ic));
+            data_cell.input_contexts.insert(
+                ic,
+                InputContextData {
+                    ic: InputContext(ic),
+                    state: IcState::empty(),
+                    pending_syncs: VecDeque::new(),
+                    pending_geometry: Vec::new(),
+                    areas: area_attrs(&InputContext(ic)),
+                    preedit: Default::default(),
+                    max_preedit_length: None,
+                },
+            );
             None
         }
         (ffi::XCB_XIM_DESTROY_IC, ic) => ic,
         _ => None,
     };
 
+    // Track the protocol state changed by the client
+    let mut replied_sync = None;
+    if let Some(ic_data) = ic_ptr_opt.and_then(|ic| data_cell.input_contexts.get_mut(&ic)) {
+        match raw_args.major_opcode as u32 {
+            ffi::XCB_XIM_SET_IC_FOCUS => ic_data.state.insert(IcState::FOCUSED),
+            ffi::XCB_XIM_UNSET_IC_FOCUS => ic_data.state.remove(IcState::FOCUSED),
+            ffi::XCB_XIM_SYNC_REPLY => {
+                replied_sync = ic_data.pending_syncs.pop_front();
+                if ic_data.pending_syncs.is_empty() {
+                    ic_data.state.remove(IcState::SYNC_PENDING);
+                }
+            }
+            ffi::XCB_XIM_PREEDIT_START_REPLY => {
+                let frame = unsafe { &*(frame as *const ffi::xcb_im_preedit_start_reply_fr_t) };
+                let return_value = frame.return_value as i32;
+                ic_data.max_preedit_length = if return_value < 0 {
+                    None
+                } else {
+                    Some(return_value as usize)
+                };
+            }
+            _ => (),
+        }
+    }
+
     // Call handler
     let im_ref = ImServerRef(data_ptr);
     handle_callback(&im_ref, &raw_args, &mut **data_cell.handler.borrow_mut());
 
+    if let (Some(pending_sync), Some(ic)) = (replied_sync, ic_opt.as_ref()) {
+        (pending_sync.on_done)(&im_ref, ic, SyncResult::Replied);
+    }
+
+    // Resolve the geometry requests once the client sets the area
+    if let (ffi::XCB_XIM_SET_IC_VALUES, Some(ic)) = (raw_args.major_opcode as u32, ic_opt.as_ref())
+    {
+        let areas = area_attrs(ic);
+        let callbacks = match im_ref.get_ic_data(ic) {
+            Some(ic_data) if ic_data.areas != areas => {
+                ic_data.areas = areas;
+                mem::take(&mut ic_data.pending_geometry)
+            }
+            _ => Vec::new(),
+        };
+        let geometry = ic.get_geometry();
+        for on_done in callbacks {
+            on_done(&im_ref, ic, geometry);
+        }
+    }
+
     if let Some(ic) = destroyed_ic {
         data_cell.input_contexts.remove(&ic);
     }
@@ -236,10 +496,14 @@ impl<'a> fmt::Debug for ImServer<'a> {
 }
 
 impl ImServerRef {
-    pub fn forward_event(&self, ic: &InputContext, event: &xcb::KeyPressEvent) {
-        unsafe { ffi::xcb_im_forward_event(self.get_im_ptr(), ic.as_ptr(), event.ptr) }
+    /// Forwards the event back to the client.
+    pub fn forward_event<T>(&self, ic: &InputContext, event: &xcb::Event<T>) {
+        // xcb-imdkit sends the event as a 32-byte core event regardless of its type
+        let event_ptr = event.ptr as *mut xcb::ffi::xcb_key_press_event_t;
+        unsafe { ffi::xcb_im_forward_event(self.get_im_ptr(), ic.as_ptr(), event_ptr) }
     }
 
+    /// Commits the string to the client.
     pub fn commit_string(&self, ic: &InputContext, committed_str: &CommittedString) {
         use CommittedString::*;
         let flag = match committed_str {
@@ -274,15 +538,91 @@ impl ImServerRef {
         }
     }
 
+    /// Converts the text to the encoding of the client and commits it.
+    pub fn commit(&self, ic: &InputContext, commit: &Commit) -> Result<(), ConvertError> {
+        let committed_str = match commit {
+            Commit::KeySym(x) => {
+                self.commit_string(ic, &CommittedString::KeySym(*x));
+                return Ok(());
+            }
+            Commit::Text(s) | Commit::Both(_, s) => self.get_encoding().encode(s)?,
+        };
+
+        match commit {
+            Commit::Both(x, _) => {
+                self.commit_string(ic, &CommittedString::Both(*x, &committed_str))
+            }
+            _ => self.commit_string(ic, &CommittedString::Chars(&committed_str)),
+        }
+        Ok(())
+    }
+
+    /// Converts `s` to the encoding of the client and commits it.
+    pub fn commit_str(&self, ic: &InputContext, s: &str) -> Result<(), ConvertError> {
+        let committed_str = self.get_encoding().encode(s)?;
+        self.commit_string(ic, &CommittedString::Chars(&committed_str));
+        Ok(())
+    }
+
+    /// Returns the encoding of strings sent to clients.
+    ///
+    /// xcb-imdkit does not tell which encoding each client has chosen by ENCODING_NEGOTIATION,
+    /// so COMPOUND_TEXT, which clients fall back to if no encoding matches, is used.
+    /// UTF-8 is used only if it is the only encoding in `encoding_list` given to
+    /// `ImServer::create`.
+    pub fn get_encoding(&self) -> Encoding {
+        self.get_data().encoding
+    }
+
     pub fn geometry_callback(&self, ic: &InputContext) {
         unsafe { ffi::xcb_im_geometry_callback(self.get_im_ptr(), ic.as_ptr()) }
     }
 
+    /// Sends XIM_GEOMETRY and calls `on_done` when the client sets `XNArea` or `XNAreaNeeded`
+    /// of the preedit or the status by SET_IC_VALUES.
+    ///
+    /// xcb-imdkit does not tell which attributes a SET_IC_VALUES carries, so only SET_IC_VALUES
+    /// changing the areas resolves `on_done`. Setting the same areas again does not.
+    ///
+    /// `on_done` is dropped without being called if the IC is destroyed.
+    pub fn request_geometry(
+        &self,
+        ic: &InputContext,
+        on_done: impl FnOnce(&ImServerRef, &InputContext, Geometry) + 'static,
+    ) {
+        if let Some(ic_data) = self.get_ic_data(ic) {
+            ic_data.pending_geometry.push(Box::new(on_done));
+        }
+        self.geometry_callback(ic);
+    }
+
+    /// Sends PREEDIT_START unless the preedit of `ic` has already been started.
     pub fn preedit_start_callback(&self, ic: &InputContext) {
+        if self.replace_ic_state(ic, IcState::PREEDIT_ACTIVE, true) {
+            return;
+        }
         unsafe { ffi::xcb_im_preedit_start_callback(self.get_im_ptr(), ic.as_ptr()) }
     }
 
-    pub fn preedit_draw_callback(&self, ic: &InputContext, frame: &PreeditDrawMessage) {
+    /// Sends PREEDIT_DRAW. PREEDIT_START is sent first if the preedit has not been started.
+    ///
+    /// Returns an error without sending anything unless `feedback_array` has one `Feedback` per
+    /// character of `preedit_string`.
+    /// The frame is sent as it is, without checking `get_max_preedit_length`.
+    /// Use `set_preedit` to keep the preedit within the length.
+    pub fn preedit_draw_callback(
+        &self,
+        ic: &InputContext,
+        frame: &PreeditDrawMessage,
+    ) -> Result<(), DrawError> {
+        self.check_feedback_length(frame.preedit_string, frame.status, frame.feedback_array)?;
+        self.send_preedit_draw(ic, frame);
+        Ok(())
+    }
+
+    fn send_preedit_draw(&self, ic: &InputContext, frame: &PreeditDrawMessage) {
+        self.preedit_start_callback(ic);
+
         let mut frame = ffi::xcb_im_preedit_draw_fr_t {
             input_method_ID: 0,  // set by xcb-imdkit
             input_context_ID: 0, // set by xcb-imdkit
@@ -301,7 +641,252 @@ impl ImServerRef {
         unsafe { ffi::xcb_im_preedit_draw_callback(self.get_im_ptr(), ic.as_ptr(), &mut frame) }
     }
 
+    /// Sends the preedit by PREEDIT_DRAW if `ic` uses `PREEDIT_CALLBACKS` (OnTheSpot).
+    ///
+    /// Returns `false` without sending anything if the server has to draw the preedit by itself
+    /// (OverTheSpot, OffTheSpot and Root), or if the preedit is not shown.
+    /// Like `preedit_draw_callback`, this checks `feedback_array` but not
+    /// `get_max_preedit_length`.
+    pub fn draw_preedit(
+        &self,
+        ic: &InputContext,
+        frame: &PreeditDrawMessage,
+    ) -> Result<bool, DrawError> {
+        match ic.style_kind() {
+            Some(StyleKind::OnTheSpot) => {
+                self.preedit_draw_callback(ic, frame)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Replaces the preedit of `ic` with `text`.
+    ///
+    /// `spans` are byte ranges of `text` with their feedback, and `caret` is a byte offset.
+    /// Only the part changed from the previous call is sent by PREEDIT_DRAW, and PREEDIT_START and
+    /// PREEDIT_DONE are sent as the preedit becomes non-empty or empty.
+    /// Nothing is sent unless `ic` uses `PREEDIT_CALLBACKS`.
+    ///
+    /// If the preedit is longer than the client accepts, it is handled according to
+    /// `ImServer::preedit_length_policy`.
+    ///
+    /// # Panics
+    /// Panics if `caret` or `spans` are not on char boundaries of `text`.
+    pub fn set_preedit(
+        &self,
+        ic: &InputContext,
+        text: &str,
+        spans: &[(Range<usize>, Feedback)],
+        caret: usize,
+    ) -> Result<(), PreeditError> {
+        let mut new_preedit = Preedit::new(text, spans, caret);
+        let on_the_spot = ic.style_kind() == Some(StyleKind::OnTheSpot);
+
+        if on_the_spot {
+            self.fit_preedit(ic, &mut new_preedit)?;
+
+            // The preedit may have been finished by preedit_done_callback
+            let old_preedit = match self.get_ic_data(ic) {
+                Some(ic_data) if ic_data.state.contains(IcState::PREEDIT_ACTIVE) => {
+                    ic_data.preedit.clone()
+                }
+                _ => Default::default(),
+            };
+            self.send_preedit_delta(ic, &old_preedit, &new_preedit)?;
+        }
+
+        if let Some(ic_data) = self.get_ic_data(ic) {
+            ic_data.preedit = new_preedit;
+        }
+        Ok(())
+    }
+
+    /// Moves the caret to `byte_offset` of the text given to `set_preedit`.
+    ///
+    /// Use `grapheme_byte_offset` to place the caret by grapheme clusters.
+    /// Returns `false` without sending anything if `byte_offset` is not on a char boundary of the
+    /// preedit.
+    pub fn set_preedit_caret(&self, ic: &InputContext, byte_offset: usize) -> bool {
+        let position = self
+            .get_ic_data(ic)
+            .and_then(|ic_data| ic_data.preedit.char_offset(byte_offset));
+        match position {
+            Some(position) => {
+                self.send_preedit_caret(ic, position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Moves the caret of the preedit set by `set_preedit` by a character, or to the start or end.
+    ///
+    /// Returns `false` without sending anything if the caret would leave the preedit, or for
+    /// the directions by words and lines, which depend on how the client lays out the text.
+    pub fn move_caret(&self, ic: &InputContext, direction: CaretDirection) -> bool {
+        let position = self
+            .get_ic_data(ic)
+            .and_then(|ic_data| ic_data.preedit.moved_caret(direction));
+        match position {
+            Some(position) => {
+                self.send_preedit_caret(ic, position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn send_preedit_caret(&self, ic: &InputContext, position: usize) {
+        let active = match self.get_ic_data(ic) {
+            Some(ic_data) => {
+                ic_data.preedit.caret = position;
+                ic_data.state.contains(IcState::PREEDIT_ACTIVE)
+            }
+            None => false,
+        };
+        if active && ic.style_kind() == Some(StyleKind::OnTheSpot) {
+            self.preedit_caret_callback(
+                ic,
+                &PreeditCaretMessage {
+                    position: position as i32,
+                    direction: CaretDirection::AbsolutePosition,
+                    style: CaretStyle::Primary,
+                },
+            );
+        }
+    }
+
+    /// Handles RESET_IC with the preedit set by `set_preedit`, and returns the reply.
+    ///
+    /// The default `ImMessageHandler::handle_reset_ic` calls this with
+    /// `PreeditResetAction::Return`. If the preedit can't be converted to the encoding of the
+    /// client, nothing is returned or committed.
+    pub fn reset_preedit(
+        &self,
+        ic: &InputContext,
+        action: PreeditResetAction,
+    ) -> ResetIcReplyMessage {
+        let text = match self.get_ic_data(ic) {
+            Some(ic_data) => ic_data.preedit.text(),
+            None => return Default::default(),
+        };
+
+        let mut reply = ResetIcReplyMessage::default();
+        match action {
+            PreeditResetAction::Return if !text.is_empty() => {
+                reply.preedit_string = self
+                    .get_encoding()
+                    .encode(&text)
+                    .map_or_else(|_| Vec::new(), |x| x.into_owned());
+            }
+            PreeditResetAction::Commit if !text.is_empty() => {
+                let _ = self.commit_str(ic, &text);
+            }
+            PreeditResetAction::Keep => return reply,
+            _ => (),
+        }
+
+        // An empty preedit can always be converted
+        let _ = self.set_preedit(ic, "", &[], 0);
+        reply
+    }
+
+    /// Returns the maximum bytes of the preedit answered by PREEDIT_START_REPLY.
+    /// `None` if unlimited or not answered yet.
+    pub fn get_max_preedit_length(&self, ic: &InputContext) -> Option<usize> {
+        self.get_ic_data(ic).and_then(|x| x.max_preedit_length)
+    }
+
+    fn fit_preedit(&self, ic: &InputContext, preedit: &mut Preedit) -> Result<(), PreeditError> {
+        let max_length = match self.get_max_preedit_length(ic) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let byte_length = |len: usize| -> Result<usize, ConvertError> {
+            let s = preedit.chars[..len].iter().collect::<String>();
+            Ok(self.get_encoding().encode(&s)?.len())
+        };
+
+        if byte_length(preedit.chars.len())? <= max_length {
+            return Ok(());
+        }
+
+        if self.get_data().preedit_length_policy == PreeditLengthPolicy::Error {
+            return Err(PreeditError::TooLong { max_length });
+        }
+
+        // Find the longest prefix that fits
+        let (mut ok, mut ng) = (0, preedit.chars.len());
+        while ng - ok > 1 {
+            let mid = (ok + ng) / 2;
+            if byte_length(mid)? <= max_length {
+                ok = mid;
+            } else {
+                ng = mid;
+            }
+        }
+        preedit.truncate(ok);
+        Ok(())
+    }
+
+    fn send_preedit_delta(
+        &self,
+        ic: &InputContext,
+        old_preedit: &Preedit,
+        new_preedit: &Preedit,
+    ) -> Result<(), ConvertError> {
+        if new_preedit.is_empty() {
+            if !old_preedit.is_empty() {
+                self.send_preedit_draw(
+                    ic,
+                    &PreeditDrawMessage {
+                        caret: 0,
+                        chg_first: 0,
+                        chg_length: old_preedit.chars.len() as i32,
+                        status: DrawStatus::NO_STRING | DrawStatus::NO_FEEDBACK,
+                        preedit_string: &[],
+                        feedback_array: &[],
+                    },
+                );
+            }
+            self.preedit_done_callback(ic);
+            return Ok(());
+        }
+
+        if old_preedit == new_preedit {
+            return Ok(());
+        }
+
+        let delta = preedit::diff(old_preedit, new_preedit);
+        let inserted = new_preedit.chars[delta.inserted.clone()]
+            .iter()
+            .collect::<String>();
+        let preedit_string = self.get_encoding().encode(&inserted)?;
+        let feedback_array = &new_preedit.feedback[delta.inserted];
+
+        let mut status = DrawStatus::empty();
+        status.set(DrawStatus::NO_STRING, preedit_string.is_empty());
+        status.set(DrawStatus::NO_FEEDBACK, feedback_array.is_empty());
+
+        self.send_preedit_draw(
+            ic,
+            &PreeditDrawMessage {
+                caret: new_preedit.caret as i32,
+                chg_first: delta.chg_first as i32,
+                chg_length: delta.chg_length as i32,
+                status,
+                preedit_string: &preedit_string,
+                feedback_array,
+            },
+        );
+        Ok(())
+    }
+
+    /// Sends PREEDIT_CARET. PREEDIT_START is sent first if the preedit has not been started.
     pub fn preedit_caret_callback(&self, ic: &InputContext, frame: &PreeditCaretMessage) {
+        self.preedit_start_callback(ic);
+
         let mut frame = ffi::xcb_im_preedit_caret_fr_t {
             input_method_ID: 0,  // set by xcb-imdkit
             input_context_ID: 0, // set by xcb-imdkit
@@ -313,15 +898,39 @@ impl ImServerRef {
         unsafe { ffi::xcb_im_preedit_caret_callback(self.get_im_ptr(), ic.as_ptr(), &mut frame) }
     }
 
+    /// Sends PREEDIT_DONE if the preedit of `ic` has been started.
     pub fn preedit_done_callback(&self, ic: &InputContext) {
+        if !self.replace_ic_state(ic, IcState::PREEDIT_ACTIVE, false) {
+            return;
+        }
         unsafe { ffi::xcb_im_preedit_done_callback(self.get_im_ptr(), ic.as_ptr()) }
     }
 
+    /// Sends STATUS_START unless the status of `ic` has already been started.
     pub fn status_start_callback(&self, ic: &InputContext) {
+        if self.replace_ic_state(ic, IcState::STATUS_ACTIVE, true) {
+            return;
+        }
         unsafe { ffi::xcb_im_status_start_callback(self.get_im_ptr(), ic.as_ptr()) }
     }
 
-    pub fn status_draw_text_callback(&self, ic: &InputContext, frame: &StatusDrawTextMessage) {
+    /// Sends STATUS_DRAW. STATUS_START is sent first if the status has not been started.
+    ///
+    /// Returns an error without sending anything unless `feedback_array` has one `Feedback` per
+    /// character of `status_string`.
+    pub fn status_draw_text_callback(
+        &self,
+        ic: &InputContext,
+        frame: &StatusDrawTextMessage,
+    ) -> Result<(), DrawError> {
+        self.check_feedback_length(frame.status_string, frame.status, frame.feedback_array)?;
+        self.send_status_draw_text(ic, frame);
+        Ok(())
+    }
+
+    fn send_status_draw_text(&self, ic: &InputContext, frame: &StatusDrawTextMessage) {
+        self.status_start_callback(ic);
+
         let mut frame = ffi::xcb_im_status_draw_text_fr_t {
             input_method_ID: 0,          // set by xcb-imdkit
             input_context_ID: 0,         // set by xcb-imdkit
@@ -338,7 +947,10 @@ impl ImServerRef {
         unsafe { ffi::xcb_im_status_draw_text_callback(self.get_im_ptr(), ic.as_ptr(), &mut frame) }
     }
 
+    /// Sends STATUS_DRAW. STATUS_START is sent first if the status has not been started.
     pub fn status_draw_bitmap_callback(&self, ic: &InputContext, frame: &StatusDrawBitmapMessage) {
+        self.status_start_callback(ic);
+
         let mut frame = ffi::xcb_im_status_draw_bitmap_fr_t {
             input_method_ID: 0,            // set by xcb-imdkit
             input_context_ID: 0,           // set by xcb-imdkit
@@ -351,28 +963,178 @@ impl ImServerRef {
         }
     }
 
+    /// Sends STATUS_DONE if the status of `ic` has been started.
     pub fn status_done_callback(&self, ic: &InputContext) {
+        if !self.replace_ic_state(ic, IcState::STATUS_ACTIVE, false) {
+            return;
+        }
         unsafe { ffi::xcb_im_status_done_callback(self.get_im_ptr(), ic.as_ptr()) }
     }
 
+    /// Shows `status` by STATUS_DRAW, or hides it by STATUS_DONE.
+    ///
+    /// Nothing is sent unless `ic` uses `STATUS_CALLBACKS`. With `STATUS_AREA` the server draws
+    /// the status by itself, and `STATUS_NOTHING`/`STATUS_NONE` show no status.
+    ///
+    /// # Panics
+    /// Panics if `spans` are not on char boundaries of the text.
+    pub fn set_status(&self, ic: &InputContext, status: &Status) -> Result<(), ConvertError> {
+        if !ic.get_input_style().contains(InputStyle::STATUS_CALLBACKS) {
+            return Ok(());
+        }
+
+        match status {
+            Status::Text { text, spans } => {
+                let status_string = self.get_encoding().encode(text)?;
+                let feedback_array = spans
+                    .iter()
+                    .fold(FeedbackBuilder::new(text), |builder, (range, x)| {
+                        builder.range(range.clone(), *x)
+                    })
+                    .build();
+
+                let mut status = DrawStatus::empty();
+                status.set(DrawStatus::NO_STRING, status_string.is_empty());
+                status.set(DrawStatus::NO_FEEDBACK, feedback_array.is_empty());
+
+                self.send_status_draw_text(
+                    ic,
+                    &StatusDrawTextMessage {
+                        status,
+                        status_string: &status_string,
+                        feedback_array: &feedback_array,
+                    },
+                );
+            }
+            Status::Bitmap(pixmap_data) => self.status_draw_bitmap_callback(
+                ic,
+                &StatusDrawBitmapMessage {
+                    pixmap_data: *pixmap_data,
+                },
+            ),
+            Status::Hidden => self.status_done_callback(ic),
+        }
+        Ok(())
+    }
+
+    /// Asks the client to forward the events in `event_mask` given to `ImServer::create`.
+    ///
+    /// xcb-imdkit sends XIM_SET_EVENT_MASK only when trigger keys are registered.
     pub fn preedit_start(&self, ic: &InputContext) {
         unsafe { ffi::xcb_im_preedit_start(self.get_im_ptr(), ic.as_ptr()) }
     }
 
+    /// Asks the client to stop forwarding events.
+    ///
+    /// xcb-imdkit sends XIM_SET_EVENT_MASK only when trigger keys are registered.
     pub fn preedit_end(&self, ic: &InputContext) {
         unsafe { ffi::xcb_im_preedit_end(self.get_im_ptr(), ic.as_ptr()) }
     }
 
+    /// Sends XIM_SYNC without waiting for the reply.
     pub fn sync_xlib(&self, ic: &InputContext) {
         unsafe { ffi::xcb_im_sync_xlib(self.get_im_ptr(), ic.as_ptr()) }
     }
 
+    /// Sends XIM_SYNC and calls `on_done` when the client replies.
+    ///
+    /// If no reply arrives within `timeout`, `on_done` is called with `SyncResult::TimedOut`
+    /// by `ImServer::filter_event` or `check_sync_timeouts`.
+    /// `on_done` is dropped without being called if the IC is destroyed.
+    ///
+    /// SYNC_REPLY does not tell which frame it answers, so replies are matched to the calls in
+    /// order. This is reliable only when nothing else synchronous is in flight: a reply to
+    /// `sync_xlib`, to a commit or forwarded event sent as synchronous, or to a call which has
+    /// timed out resolves the next call too early.
+    pub fn sync(
+        &self,
+        ic: &InputContext,
+        timeout: Duration,
+        on_done: impl FnOnce(&ImServerRef, &InputContext, SyncResult) + 'static,
+    ) {
+        self.sync_xlib(ic);
+        if let Some(ic_data) = self.get_ic_data(ic) {
+            ic_data.pending_syncs.push_back(PendingSync {
+                deadline: Instant::now() + timeout,
+                on_done: Box::new(on_done),
+            });
+            ic_data.state.insert(IcState::SYNC_PENDING);
+        }
+    }
+
+    /// Calls the callbacks of `sync` whose timeout has expired.
+    pub fn check_sync_timeouts(&self) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+
+        for ic_data in self.get_data().input_contexts.values_mut() {
+            for pending_sync in mem::take(&mut ic_data.pending_syncs) {
+                if pending_sync.deadline <= now {
+                    expired.push((ic_data.ic.0, pending_sync));
+                } else {
+                    ic_data.pending_syncs.push_back(pending_sync);
+                }
+            }
+            if ic_data.pending_syncs.is_empty() {
+                ic_data.state.remove(IcState::SYNC_PENDING);
+            }
+        }
+
+        for (ic, pending_sync) in expired {
+            let ic = InputContext(ic);
+            (pending_sync.on_done)(self, &ic, SyncResult::TimedOut);
+        }
+    }
+
     pub fn support_extension(&self, major_code: u16, minor_code: u16) -> bool {
         unsafe { ffi::xcb_im_support_extension(self.get_im_ptr(), major_code, minor_code) }
     }
 
     pub fn get_ic(&self, ic_ptr: *mut ffi::xcb_im_input_context_t) -> Option<&InputContext> {
-        NonNull::new(ic_ptr).and_then(|p| self.get_data().input_contexts.get(&p))
+        NonNull::new(ic_ptr)
+            .and_then(|p| self.get_data().input_contexts.get(&p))
+            .map(|x| &x.ic)
+    }
+
+    pub fn get_ic_state(&self, ic: &InputContext) -> IcState {
+        self.get_ic_data(ic)
+            .map_or_else(IcState::empty, |x| x.state)
+    }
+
+    fn check_feedback_length(
+        &self,
+        string: &[u8],
+        status: DrawStatus,
+        feedback_array: &[Feedback],
+    ) -> Result<(), DrawError> {
+        if status.intersects(DrawStatus::NO_STRING | DrawStatus::NO_FEEDBACK) {
+            return Ok(());
+        }
+        let chars = self.get_encoding().decode(string)?.chars().count();
+        if chars == feedback_array.len() {
+            Ok(())
+        } else {
+            Err(DrawError::FeedbackLength {
+                chars,
+                feedbacks: feedback_array.len(),
+            })
+        }
+    }
+
+    fn get_ic_data(&self, ic: &InputContext) -> Option<&mut InputContextData> {
+        self.get_data().input_contexts.get_mut(&ic.0)
+    }
+
+    /// Sets or clears `flag` and returns whether it was set before.
+    fn replace_ic_state(&self, ic: &InputContext, flag: IcState, value: bool) -> bool {
+        match self.get_ic_data(ic) {
+            Some(ic_data) => {
+                let old = ic_data.state.contains(flag);
+                ic_data.state.set(flag, value);
+                old
+            }
+            None => !value,
+        }
     }
 
     #[inline]
@@ -398,6 +1160,28 @@ impl fmt::Debug for ImServerRef {
     }
 }
 
+impl fmt::Debug for InputContextData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("InputContextData")
+            .field("ic", &self.ic)
+            .field("state", &self.state)
+            .field("pending_syncs", &self.pending_syncs)
+            .field("pending_geometry", &self.pending_geometry.len())
+            .field("areas", &self.areas)
+            .field("preedit", &self.preedit)
+            .field("max_preedit_length", &self.max_preedit_length)
+            .finish()
+    }
+}
+
+impl fmt::Debug for PendingSync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PendingSync")
+            .field("deadline", &self.deadline)
+            .finish()
+    }
+}
+
 impl fmt::Debug for ImServerData {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("ImServerData")
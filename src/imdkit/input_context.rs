@@ -13,6 +13,10 @@ impl InputContext {
         InputStyle::from_bits_truncate(bits)
     }
 
+    pub fn style_kind(&self) -> Option<StyleKind> {
+        self.get_input_style().kind()
+    }
+
     pub fn get_client_window(&self) -> xcb::Window {
         unsafe { ffi::xcb_im_input_context_get_client_window(self.as_ptr()) }
     }
@@ -25,6 +29,43 @@ impl InputContext {
         unsafe { &*ffi::xcb_im_input_context_get_status_attr(self.as_ptr()) }
     }
 
+    pub fn get_preedit_attr_mask(&self) -> AttrMask {
+        let bits = unsafe { ffi::xcb_im_input_context_get_preedit_attr_mask(self.as_ptr()) };
+        AttrMask::from_bits_truncate(bits)
+    }
+
+    pub fn get_status_attr_mask(&self) -> AttrMask {
+        let bits = unsafe { ffi::xcb_im_input_context_get_status_attr_mask(self.as_ptr()) };
+        AttrMask::from_bits_truncate(bits)
+    }
+
+    pub fn get_geometry(&self) -> Geometry {
+        fn area(mask: AttrMask, area: Rectangle, area_needed: Rectangle) -> Option<Rectangle> {
+            if mask.contains(AttrMask::AREA) {
+                Some(area)
+            } else if mask.contains(AttrMask::AREA_NEEDED) {
+                Some(area_needed)
+            } else {
+                None
+            }
+        }
+
+        let preedit_attr = self.get_preedit_attr();
+        let status_attr = self.get_status_attr();
+        Geometry {
+            preedit_area: area(
+                self.get_preedit_attr_mask(),
+                preedit_attr.area.into(),
+                preedit_attr.area_needed.into(),
+            ),
+            status_area: area(
+                self.get_status_attr_mask(),
+                status_attr.area.into(),
+                status_attr.area_needed.into(),
+            ),
+        }
+    }
+
     pub fn as_ptr(&self) -> *mut ffi::xcb_im_input_context_t {
         self.0.as_ptr()
     }
@@ -6,11 +6,13 @@ mod data_types;
 mod im_message;
 mod im_server;
 mod input_context;
+mod preedit;
 
 pub use self::data_types::*;
 pub use self::im_message::*;
 pub use self::im_server::*;
 pub use self::input_context::*;
+pub use self::preedit::{char_offset, grapheme_byte_offset};
 
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub struct ImClient(NonNull<ffi::xcb_im_client_t>);
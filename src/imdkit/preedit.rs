@@ -0,0 +1,172 @@
+use super::data_types::{CaretDirection, Feedback, FeedbackBuilder};
+use std::ops::Range;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Converts a byte offset of `text` to XIM character units.
+/// Returns `None` if `byte_offset` is not on a char boundary of `text`.
+pub fn char_offset(text: &str, byte_offset: usize) -> Option<usize> {
+    text.get(..byte_offset).map(|x| x.chars().count())
+}
+
+/// Converts an index of grapheme clusters of `text` to a byte offset.
+/// The number of grapheme clusters is converted to `text.len()`.
+pub fn grapheme_byte_offset(text: &str, grapheme_index: usize) -> Option<usize> {
+    text.grapheme_indices(true)
+        .map(|(i, _)| i)
+        .chain(Some(text.len()))
+        .nth(grapheme_index)
+}
+
+/// The preedit shown to the client, in XIM character units.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) struct Preedit {
+    pub chars: Vec<char>,
+    pub feedback: Vec<Feedback>,
+    pub caret: usize,
+}
+
+impl Preedit {
+    /// `spans` and `caret` are byte offsets into `text`.
+    ///
+    /// # Panics
+    /// Panics if `caret` or `spans` are not on char boundaries of `text`.
+    pub fn new(text: &str, spans: &[(Range<usize>, Feedback)], caret: usize) -> Self {
+        let feedback = spans
+            .iter()
+            .fold(FeedbackBuilder::new(text), |builder, (range, x)| {
+                builder.range(range.clone(), *x)
+            })
+            .build();
+
+        Preedit {
+            chars: text.chars().collect(),
+            feedback,
+            caret: text[..caret].chars().count(),
+        }
+    }
+
+    pub fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chars.is_empty()
+    }
+
+    /// Returns the caret after moving to `direction`, or `None` if it is out of the preedit or
+    /// `direction` needs the layout of the text.
+    pub fn moved_caret(&self, direction: CaretDirection) -> Option<usize> {
+        match direction {
+            CaretDirection::ForwardChar if self.caret < self.chars.len() => Some(self.caret + 1),
+            CaretDirection::BackwardChar => self.caret.checked_sub(1),
+            CaretDirection::LineStart => Some(0),
+            CaretDirection::LineEnd => Some(self.chars.len()),
+            CaretDirection::DontChange => Some(self.caret),
+            _ => None,
+        }
+    }
+
+    /// Converts a byte offset of the text to XIM character units.
+    pub fn char_offset(&self, byte_offset: usize) -> Option<usize> {
+        let mut offset = 0;
+        for (i, c) in self.chars.iter().enumerate() {
+            if offset == byte_offset {
+                return Some(i);
+            }
+            offset += c.len_utf8();
+        }
+        if offset == byte_offset {
+            Some(self.chars.len())
+        } else {
+            None
+        }
+    }
+
+    /// Keeps the first `len` characters.
+    pub fn truncate(&mut self, len: usize) {
+        self.chars.truncate(len);
+        self.feedback.truncate(len);
+        self.caret = self.caret.min(len);
+    }
+}
+
+/// The smallest replacement turning one preedit into another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PreeditDelta {
+    pub chg_first: usize,
+    pub chg_length: usize,
+    /// The range of the new preedit replacing the changed range
+    pub inserted: Range<usize>,
+}
+
+pub(crate) fn diff(old: &Preedit, new: &Preedit) -> PreeditDelta {
+    let same =
+        |i: usize, j: usize| old.chars[i] == new.chars[j] && old.feedback[i] == new.feedback[j];
+
+    let max_len = old.chars.len().min(new.chars.len());
+    let prefix = (0..max_len).take_while(|&i| same(i, i)).count();
+    let suffix = (0..max_len - prefix)
+        .take_while(|&i| same(old.chars.len() - 1 - i, new.chars.len() - 1 - i))
+        .count();
+
+    PreeditDelta {
+        chg_first: prefix,
+        chg_length: old.chars.len() - prefix - suffix,
+        inserted: prefix..new.chars.len() - suffix,
+    }
+}
+
+#[test]
+fn preedit_diff_test() {
+    let underline = Feedback::UNDERLINE;
+    let old = Preedit::new("にほんご", &[(0..12, underline)], 12);
+
+    let appended = Preedit::new("にほんごを", &[(0..15, underline)], 15);
+    let delta = diff(&old, &appended);
+    assert_eq!(delta.chg_first, 4);
+    assert_eq!(delta.chg_length, 0);
+    assert_eq!(delta.inserted, 4..5);
+
+    let converted = Preedit::new("日本ご", &[(0..9, underline)], 9);
+    let delta = diff(&old, &converted);
+    assert_eq!(delta.chg_first, 0);
+    assert_eq!(delta.chg_length, 3);
+    assert_eq!(delta.inserted, 0..2);
+
+    // A feedback change is a change even if the text is the same
+    let reversed = Preedit::new(
+        "にほんご",
+        &[(0..6, underline), (6..12, Feedback::REVERSE)],
+        6,
+    );
+    assert_eq!(reversed.caret, 2);
+    let delta = diff(&old, &reversed);
+    assert_eq!(delta.chg_first, 2);
+    assert_eq!(delta.chg_length, 2);
+    assert_eq!(delta.inserted, 2..4);
+
+    let delta = diff(&old, &old);
+    assert_eq!(delta.chg_length, 0);
+    assert_eq!(delta.inserted, 4..4);
+}
+
+#[test]
+fn caret_offset_test() {
+    // "が" in two code points
+    let text = "か\u{3099}な";
+    assert_eq!(char_offset(text, 6), Some(2));
+    assert_eq!(char_offset(text, 1), None);
+    assert_eq!(grapheme_byte_offset(text, 1), Some(6));
+    assert_eq!(grapheme_byte_offset(text, 2), Some(9));
+    assert_eq!(grapheme_byte_offset(text, 3), None);
+
+    let preedit = Preedit::new(text, &[], 6);
+    assert_eq!(preedit.char_offset(9), Some(3));
+    assert_eq!(preedit.char_offset(4), None);
+    assert_eq!(preedit.moved_caret(CaretDirection::ForwardChar), Some(3));
+    assert_eq!(preedit.moved_caret(CaretDirection::LineStart), Some(0));
+
+    let at_end = Preedit::new(text, &[], 9);
+    assert_eq!(at_end.moved_caret(CaretDirection::ForwardChar), None);
+    assert_eq!(at_end.moved_caret(CaretDirection::ForwardWord), None);
+}